@@ -1,16 +1,17 @@
 use log::{ info, error, debug };
-use libdnssd::{ ServiceDiscovery, IpType };
+use std::time::Duration;
+use libdnssd::{ ServiceDiscovery, ServiceEvent, IpType, AddressPreference };
 
 fn main() {
     env_logger::init();
 
-    find_ipv4_service("_matterc._udp.local");
-    find_ipv6_service("_matterc._udp.local");
+    find_service("_matterc._udp.local");
+    resolve_service("_matterc._udp.local");
 }
 
-fn find_ipv4_service(service: &str)
+fn find_service(service: &str)
 {
-    let mut service_discovery = match ServiceDiscovery::new(IpType::V4)
+    let mut service_discovery = match ServiceDiscovery::new(IpType::DualStack)
     {
         Ok(service) => service,
         Err(err) =>
@@ -20,48 +21,41 @@ fn find_ipv4_service(service: &str)
         }
     };
 
+    // Subscribe before starting to look for the service to get all events.
+    let events = service_discovery.subscribe();
+
     // Multiple services can be found by calling find_service multiple times.
     service_discovery.find_service(service);
 
-    loop
+    // Wait for the listen thread to report a resolved instance instead of polling.
+    for event in events.iter()
     {
-        // Get IP address and port for one of the services added to be found.
-        match service_discovery.get_ip_and_port(service)
+        match event
         {
-            Some(ip_port) =>
-            {
-                info!("Found service: {}:{}", ip_port.0, ip_port.1);
-                service_discovery.stop_find_service(service);
-            },
-            None =>
+            ServiceEvent::Resolved(instance) =>
             {
-                std::thread::sleep(std::time::Duration::from_millis(200));
-                continue;
-            }
-        };
+                info!("Found service {}: {}:{}", instance.full_name(), instance.addresses[0], instance.port);
 
-        // Additionally we can also get TXT records if included for the service.
-        match service_discovery.get_txt_records(service)
-        {
-            Some(txt_records) =>
-            {
-                for txt_record in txt_records
+                // TXT records are included if the service has any.
+                for txt_record in instance.txt_records
                 {
                     debug!("TXT Record: {}", txt_record);
                 }
+
+                service_discovery.stop_find_service(service);
+                return;
             },
-            None =>
+            event =>
             {
-                break;
+                debug!("Service event: {:?}", event);
             }
         }
-        return;
     }
 }
 
-fn find_ipv6_service(service: &str)
+fn resolve_service(service: &str)
 {
-    let mut service_discovery = match ServiceDiscovery::new(IpType::V6)
+    let mut service_discovery = match ServiceDiscovery::new(IpType::DualStack)
     {
         Ok(service) => service,
         Err(err) =>
@@ -71,41 +65,26 @@ fn find_ipv6_service(service: &str)
         }
     };
 
-    // Multiple services can be found by calling find_service multiple times.
-    service_discovery.find_service(service);
+    // Both A and AAAA records are kept, list IPv6 addresses first.
+    service_discovery.set_address_preference(AddressPreference::Ipv6First);
 
-    loop
+    // Block until an instance has been resolved or the timeout passes.
+    match service_discovery.resolve(service, Duration::from_secs(10))
     {
-        // Get IP address and port for one of the services added to be found.
-        match service_discovery.get_ip_and_port(service)
+        Ok(instance) =>
         {
-            Some(ip_port) =>
-            {
-                info!("Found service: {}:{}", ip_port.0, ip_port.1);
-                service_discovery.stop_find_service(service);
-            }
-            None =>
-            {
-                std::thread::sleep(std::time::Duration::from_millis(200));
-                continue;
-            }
-        };
+            info!("Found service {}: {}:{}", instance.full_name(), instance.addresses[0], instance.port);
 
-        // Additionally we can also get TXT records if included for the service.
-        match service_discovery.get_txt_records(service)
-        {
-            Some(txt_records) =>
-            {
-                for txt_record in txt_records
-                {
-                    debug!("TXT Record: {}", txt_record);
-                }
-            },
-            None =>
+            for txt_record in instance.txt_records
             {
-                break;
+                debug!("TXT Record: {}", txt_record);
             }
+        },
+        Err(err) =>
+        {
+            error!("Failed to resolve {}: {}", service, err);
         }
-        return;
     }
+
+    service_discovery.stop_find_service(service);
 }
@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]|
+{
+    if let Some(bytes) = libdnssd::fuzzing::parse_header(data)
+    {
+        assert_eq!(bytes, data[..12]);
+    }
+});
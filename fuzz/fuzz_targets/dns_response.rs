@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]|
+{
+    libdnssd::fuzzing::parse_response(data);
+});
@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// The first byte is the offset of the name in the rest of the input.
+fuzz_target!(|data: &[u8]|
+{
+    if let Some((&offset, buffer)) = data.split_first()
+    {
+        libdnssd::fuzzing::parse_label(buffer, offset as usize);
+    }
+});
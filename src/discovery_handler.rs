@@ -1,55 +1,102 @@
-use std::collections::HashMap;
-use std::time::SystemTime;
+use std::collections::{ HashMap, HashSet };
+use std::net::IpAddr;
+use std::time::{ Duration, Instant };
 use log::debug;
 
-use crate::dns::DnsSdResponse;
+use crate::dns::{ DnsSdResponse, PtrAnswer, Question, SrvAnswer, TxtAnswer, Type };
+use crate::record_cache::{ CachedRecord, RecordCache, DEFAULT_MAX_SIZE };
+use crate::service_instance::ServiceInstance;
+use crate::service_event::ServiceEvent;
+use crate::{ IpType, AddressPreference };
 
-pub struct TimeStampedResponse
+// What has been reported about an instance through events so far.
+struct KnownInstance
 {
-    pub timestamp: SystemTime,
-    pub responses: Vec<DnsSdResponse>,
-}
-
-impl TimeStampedResponse
-{
-    pub fn new(responses: Vec<DnsSdResponse>) -> TimeStampedResponse
-    {
-        TimeStampedResponse
-        {
-            timestamp: SystemTime::now(),
-            responses,
-        }
-    }
+    service: String,
+    instance: String,
+    resolved: Option<ServiceInstance>
 }
 
 pub struct DiscoveryHandler
 {
+    ip_type: IpType,
+    address_preference: AddressPreference,
+    // Cache every record received, not only those of the services looked for.
+    cache_all: bool,
     services: Vec<String>,
-    found_services: HashMap<String, Vec<TimeStampedResponse>>
+    cache: RecordCache,
+    known_instances: HashMap<String, KnownInstance>
 }
 
 impl DiscoveryHandler
 {
-    pub fn new() -> DiscoveryHandler
+    pub fn new(ip_type: IpType) -> DiscoveryHandler
     {
         DiscoveryHandler
         {
+            ip_type,
+            address_preference: AddressPreference::Ipv4First,
+            cache_all: false,
             services: Vec::new(),
-            found_services: HashMap::new()
+            cache: RecordCache::new(DEFAULT_MAX_SIZE),
+            known_instances: HashMap::new()
         }
     }
 
+    pub fn set_address_preference(&mut self, preference: AddressPreference)
+    {
+        self.address_preference = preference;
+    }
+
+    pub fn set_cache_all(&mut self, cache_all: bool)
+    {
+        self.cache_all = cache_all;
+    }
+
+    // Whether received packets need to be parsed at all.
+    pub fn wants_responses(&self) -> bool
+    {
+        self.cache_all || !self.services.is_empty()
+    }
+
     pub fn add_service(&mut self, service: String)
     {
         debug!("Adding service: {}", service);
         self.services.push(service);
     }
 
-    pub fn add_response(&mut self, service_label: String, services: Vec<DnsSdResponse>)
+    // Responses received on the interface with the given index.
+    pub fn add_responses(&mut self, responses: Vec<DnsSdResponse>, interface: u32)
     {
-        debug!("Adding found service: {}", service_label);
-        let entry = self.found_services.entry(service_label).or_insert(Vec::new());
-        entry.push(TimeStampedResponse::new(services));
+        let now = Instant::now();
+        self.cache.remove_expired(now);
+
+        let matching = if self.cache_all
+        {
+            responses
+        }
+        else
+        {
+            self.services.iter()
+                .flat_map(|service| self.responses_for_service(service, &responses))
+                .collect()
+        };
+
+        for response in matching
+        {
+            if response.ttl() == 0
+            {
+                self.handle_goodbye(response, interface, now);
+                continue;
+            }
+
+            if response.cache_flush()
+            {
+                self.cache.flush_rrset(&response, interface, now);
+            }
+
+            self.cache.insert(response, interface, now);
+        }
     }
 
     pub fn remove_service(&mut self, service_label: String)
@@ -62,13 +109,685 @@ impl DiscoveryHandler
         }
     }
 
-    pub fn get_found_services(&self, service: &str) -> Option<&Vec<TimeStampedResponse>>
+    // A goodbye record (TTL 0) makes the cached copy of the same record expire one second later.
+    // A goodbye for the PTR record of an instance means the instance itself is gone.
+    fn handle_goodbye(&mut self, goodbye: DnsSdResponse, interface: u32, now: Instant)
+    {
+        debug!("Goodbye received for: {:?}", goodbye);
+
+        self.cache.goodbye(&goodbye, interface, now);
+
+        if let DnsSdResponse::PtrAnswer(ptr_answer) = goodbye
+        {
+            debug!("Service instance removed: {}", ptr_answer.service);
+        }
+    }
+
+    // An instance is removed while every cached PTR record pointing to it said goodbye. Once they
+    // are evicted the instance is simply unknown.
+    pub fn is_removed(&self, instance: &str) -> bool
+    {
+        let mut pointers = self.cache.iter()
+            .filter(|cached| matches!(&cached.response, DnsSdResponse::PtrAnswer(ptr_answer) if ptr_answer.service.eq_ignore_ascii_case(instance)))
+            .peekable();
+
+        pointers.peek().is_some() && pointers.all(|cached| cached.goodbye)
+    }
+
+    pub fn remove_expired(&mut self)
+    {
+        self.cache.remove_expired(Instant::now());
+    }
+
+    pub fn remove_interface(&mut self, interface: u32)
+    {
+        debug!("Removing records from interface: {}", interface);
+        self.cache.remove_interface(interface);
+    }
+
+    pub fn set_max_cache_size(&mut self, max_size: usize)
+    {
+        self.cache.set_max_size(max_size);
+    }
+
+    // Records owned by the instances of a service, newest first.
+    pub fn get_instance_records(&self, service: &str) -> Vec<&CachedRecord>
+    {
+        let mut records: Vec<&CachedRecord> = self.cache.get(service)
+            .filter_map(|cached| match &cached.response
+            {
+                DnsSdResponse::PtrAnswer(ptr_answer) => Some(ptr_answer.service.as_str()),
+                _ => None
+            })
+            .flat_map(|instance| self.cache.get(instance))
+            .collect();
+
+        records.sort_by_key(|cached| std::cmp::Reverse(cached.received));
+        records
+    }
+
+    // Names of the instances of a service that have not said goodbye, sorted.
+    pub fn get_instances(&self, service: &str) -> Vec<String>
+    {
+        let mut instances: Vec<String> = self.cache.get(service)
+            .filter(|cached| !cached.goodbye)
+            .filter_map(|cached| match &cached.response
+            {
+                DnsSdResponse::PtrAnswer(ptr_answer) => Some(ptr_answer.service.clone()),
+                _ => None
+            })
+            .collect();
+
+        // The same PTR record can be cached for several interfaces.
+        instances.sort();
+        instances.dedup();
+        instances
+    }
+
+    pub fn get_srv(&self, instance: &str) -> Option<&SrvAnswer>
+    {
+        self.cache.get(instance)
+            .filter(|cached| matches!(cached.response, DnsSdResponse::SrvAnswer(_)))
+            .max_by_key(|cached| cached.received)
+            .and_then(|cached| match &cached.response
+            {
+                DnsSdResponse::SrvAnswer(srv_answer) => Some(srv_answer),
+                _ => None
+            })
+    }
+
+    pub fn get_txt(&self, instance: &str) -> Option<&TxtAnswer>
+    {
+        self.cache.get(instance)
+            .filter(|cached| matches!(cached.response, DnsSdResponse::TxtAnswer(_)))
+            .max_by_key(|cached| cached.received)
+            .and_then(|cached| match &cached.response
+            {
+                DnsSdResponse::TxtAnswer(txt_answer) => Some(txt_answer),
+                _ => None
+            })
+    }
+
+    // All addresses of a host of the IP versions used, newest first. In dual-stack mode the
+    // preferred IP version comes first.
+    pub fn get_addresses(&self, host: &str) -> Vec<IpAddr>
+    {
+        let mut records: Vec<&CachedRecord> = self.cache.get(host).collect();
+        records.sort_by_key(|cached| std::cmp::Reverse(cached.received));
+
+        let mut addresses: Vec<IpAddr> = records.into_iter()
+            .filter_map(|cached| match (&cached.response, &self.ip_type)
+            {
+                (DnsSdResponse::AAnswer(a_answer), IpType::V4 | IpType::DualStack) => Some(IpAddr::V4(a_answer.address)),
+                (DnsSdResponse::AaaaAnswer(aaaa_answer), IpType::V6 | IpType::DualStack) => Some(IpAddr::V6(aaaa_answer.address)),
+                _ => None
+            })
+            .collect();
+
+        // Keep the newest copy of an address heard on several interfaces.
+        let mut seen = HashSet::new();
+        addresses.retain(|address| seen.insert(*address));
+
+        // Stable, so newest first still holds within each IP version.
+        let preference = self.address_preference;
+        addresses.sort_by_key(|address| match preference
+        {
+            AddressPreference::Ipv4First => address.is_ipv6(),
+            AddressPreference::Ipv6First => address.is_ipv4()
+        });
+
+        addresses
+    }
+
+    // Received time and TTL of the records a service depends on: its PTR records, the SRV and TXT
+    // records of its instances and the addresses of their hosts. Records that said goodbye or were
+    // flushed are left out.
+    pub fn refresh_records(&self, service: &str) -> Vec<(Instant, Duration)>
+    {
+        let mut names = vec![String::from(service)];
+        for instance in self.get_instances(service)
+        {
+            if let Some(srv_answer) = self.get_srv(&instance)
+            {
+                names.push(srv_answer.service.clone());
+            }
+            names.push(instance);
+        }
+
+        names.iter()
+            .flat_map(|name| self.cache.get(name))
+            .map(|cached| (cached, Duration::from_secs(cached.response.ttl() as u64)))
+            .filter(|(cached, ttl)| cached.expires >= cached.received + *ttl)
+            .map(|(cached, ttl)| (cached.received, ttl))
+            .collect()
+    }
+
+    // PTR records of a service cached from the interface with more than half of their TTL left,
+    // with the TTL set to the time remaining. Sent as known answers in queries, RFC 6762 section 7.1.
+    pub fn known_answers(&self, service: &str, interface: u32, now: Instant) -> Vec<PtrAnswer>
+    {
+        self.cache.get(service)
+            .filter(|cached| cached.interface == interface)
+            .filter_map(|cached|
+            {
+                let DnsSdResponse::PtrAnswer(ptr_answer) = &cached.response else
+                {
+                    return None;
+                };
+
+                if !more_than_half_left(cached, now)
+                {
+                    return None;
+                }
+
+                let remaining = cached.expires.saturating_duration_since(now).as_secs() as u32;
+                Some(PtrAnswer { ttl: remaining, ..ptr_answer.clone() })
+            })
+            .collect()
+    }
+
+    // Questions to send for a service on the interface: PTR to browse, SRV and TXT to resolve its
+    // instances and A or AAAA for their hosts. Records cached with more than half of their TTL left
+    // are not asked for, the PTR question is always sent and relies on known answers instead.
+    pub fn questions(&self, service: &str, interface: u32, now: Instant) -> Vec<Question>
+    {
+        let address_types: &[Type] = match self.ip_type
+        {
+            IpType::V4 => &[Type::A],
+            IpType::V6 => &[Type::AAAA],
+            IpType::DualStack => &[Type::A, Type::AAAA]
+        };
+
+        let mut questions = vec![Question { name: String::from(service), qtype: Type::PTR }];
+        for instance in self.get_instances(service)
+        {
+            let host = self.get_srv(&instance).map(|srv_answer| srv_answer.service.clone());
+
+            for qtype in [Type::SRV, Type::TXT]
+            {
+                if !self.is_fresh(&instance, qtype, interface, now)
+                {
+                    questions.push(Question { name: instance.clone(), qtype });
+                }
+            }
+
+            let Some(host) = host else
+            {
+                continue;
+            };
+
+            for qtype in address_types
+            {
+                if !self.is_fresh(&host, *qtype, interface, now)
+                {
+                    questions.push(Question { name: host.clone(), qtype: *qtype });
+                }
+            }
+        }
+
+        questions
+    }
+
+    fn is_fresh(&self, name: &str, qtype: Type, interface: u32, now: Instant) -> bool
+    {
+        self.cache.get(name)
+            .any(|cached| cached.interface == interface && cached.response.rtype() == qtype as u16 && more_than_half_left(cached, now))
+    }
+
+    // Instance with its SRV, TXT and address records, if it has an SRV record.
+    pub fn get_service_instance(&self, service: &str, instance: &str) -> Option<ServiceInstance>
     {
-        return self.found_services.get(service);
+        let srv_answer = self.get_srv(instance)?;
+        let txt_records = match self.get_txt(instance)
+        {
+            Some(txt_answer) => txt_answer.records.clone(),
+            None => Vec::new()
+        };
+        let addresses = self.get_addresses(&srv_answer.service);
+
+        let mut interfaces: Vec<u32> = self.cache.get(instance)
+            .filter(|cached| matches!(cached.response, DnsSdResponse::SrvAnswer(_)))
+            .map(|cached| cached.interface)
+            .collect();
+        interfaces.sort();
+        interfaces.dedup();
+
+        Some(ServiceInstance::new(service, instance, srv_answer, txt_records, addresses, interfaces))
+    }
+
+    pub fn get_service_instances(&self, service: &str) -> Vec<ServiceInstance>
+    {
+        self.get_instances(service)
+            .iter()
+            .filter_map(|instance| self.get_service_instance(service, instance))
+            .collect()
+    }
+
+    // Compare the cache with what has been reported so far and return the events for the changes.
+    pub fn collect_events(&mut self) -> Vec<ServiceEvent>
+    {
+        let mut events = Vec::new();
+
+        let mut current: Vec<(String, String, Option<ServiceInstance>)> = Vec::new();
+        for service in &self.services
+        {
+            for instance in self.get_instances(service)
+            {
+                let service_instance = self.get_service_instance(service, &instance)
+                    .filter(|service_instance| !service_instance.addresses.is_empty());
+                current.push((service.clone(), instance, service_instance));
+            }
+        }
+
+        let mut current_keys: HashSet<String> = HashSet::new();
+        for (service, instance, service_instance) in current
+        {
+            let key = instance.to_ascii_lowercase();
+            current_keys.insert(key.clone());
+
+            let known = self.known_instances.entry(key).or_insert_with(||
+            {
+                events.push(ServiceEvent::Found { service: service.clone(), instance: instance.clone() });
+
+                KnownInstance
+                {
+                    service,
+                    instance,
+                    resolved: None
+                }
+            });
+
+            let Some(service_instance) = service_instance else
+            {
+                continue;
+            };
+
+            match &known.resolved
+            {
+                None => events.push(ServiceEvent::Resolved(service_instance.clone())),
+                Some(resolved) if *resolved != service_instance => events.push(ServiceEvent::Updated(service_instance.clone())),
+                _ => continue
+            }
+
+            known.resolved = Some(service_instance);
+        }
+
+        let services = &self.services;
+        self.known_instances.retain(|key, known|
+        {
+            if current_keys.contains(key)
+            {
+                return true;
+            }
+
+            // Instances of services no longer browsed are forgotten without an event.
+            if services.contains(&known.service)
+            {
+                events.push(ServiceEvent::Removed { service: known.service.clone(), instance: known.instance.clone() });
+            }
+
+            false
+        });
+
+        events
+    }
+
+    // The browsed service an instance name belongs to.
+    pub fn get_service_of(&self, instance: &str) -> Option<&String>
+    {
+        self.services.iter().find(|service| is_instance_of(instance, service))
     }
 
     pub fn get_services(&self) -> &Vec<String>
     {
-        return &self.services;
+        &self.services
+    }
+
+    // Pick out the records in a packet that belong to a browsed service. PTR records are owned by
+    // the service type, SRV and TXT records by an instance of it and A/AAAA records by a host that
+    // an SRV record of the service points to, either in this packet or in an earlier one.
+    fn responses_for_service(&self, service: &str, responses: &[DnsSdResponse]) -> Vec<DnsSdResponse>
+    {
+        let mut targets: Vec<String> = Vec::new();
+        let cached = self.cache.iter().map(|cached| &cached.response);
+        for response in responses.iter().chain(cached)
+        {
+            if let DnsSdResponse::SrvAnswer(srv_answer) = response
+            {
+                if is_instance_of(&srv_answer.label, service)
+                {
+                    targets.push(srv_answer.service.clone());
+                }
+            }
+        }
+
+        responses.iter()
+            .filter(|response| match response
+            {
+                DnsSdResponse::PtrAnswer(ptr_answer) => ptr_answer.label.eq_ignore_ascii_case(service),
+                DnsSdResponse::SrvAnswer(_) | DnsSdResponse::TxtAnswer(_) => is_instance_of(response.label(), service),
+                DnsSdResponse::AAnswer(_) | DnsSdResponse::AaaaAnswer(_) =>
+                {
+                    targets.iter().any(|target| target.eq_ignore_ascii_case(response.label()))
+                },
+                DnsSdResponse::UnknownAnswer(_) =>
+                {
+                    response.label().eq_ignore_ascii_case(service)
+                        || is_instance_of(response.label(), service)
+                        || targets.iter().any(|target| target.eq_ignore_ascii_case(response.label()))
+                }
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+fn more_than_half_left(cached: &CachedRecord, now: Instant) -> bool
+{
+    let remaining = cached.expires.saturating_duration_since(now);
+    remaining > Duration::from_secs(cached.response.ttl() as u64) / 2
+}
+
+fn is_instance_of(label: &str, service: &str) -> bool
+{
+    if label.len() <= service.len() + 1
+    {
+        return false;
+    }
+
+    let (instance, suffix) = label.split_at(label.len() - service.len());
+    instance.ends_with('.') && suffix.eq_ignore_ascii_case(service)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::dns::{ AAnswer, AaaaAnswer };
+    use std::net::{ Ipv4Addr, Ipv6Addr };
+
+    fn packet() -> Vec<DnsSdResponse>
+    {
+        vec![
+            DnsSdResponse::PtrAnswer(PtrAnswer { label: String::from("_hap._tcp.local"), service: String::from("DIRIGERA._hap._tcp.local"), class: 1, ttl: 4500, cache_flush: false }),
+            DnsSdResponse::SrvAnswer(SrvAnswer { label: String::from("DIRIGERA._hap._tcp.local"), service: String::from("gw2.local"), priority: 0, weight: 0, port: 8000, class: 1, ttl: 120, cache_flush: true }),
+            DnsSdResponse::TxtAnswer(TxtAnswer { label: String::from("DIRIGERA._hap._tcp.local"), records: vec![String::from("ff=1")], class: 1, ttl: 4500, cache_flush: false }),
+            DnsSdResponse::AAnswer(AAnswer { label: String::from("gw2.local"), address: Ipv4Addr::new(192, 168, 1, 2), class: 1, ttl: 120, cache_flush: true }),
+            DnsSdResponse::PtrAnswer(PtrAnswer { label: String::from("_airplay._tcp.local"), service: String::from("TV._airplay._tcp.local"), class: 1, ttl: 4500, cache_flush: false }),
+            DnsSdResponse::AAnswer(AAnswer { label: String::from("tv.local"), address: Ipv4Addr::new(192, 168, 1, 3), class: 1, ttl: 120, cache_flush: true })
+        ]
+    }
+
+    #[test]
+    fn test_add_responses_attributes_records()
+    {
+        let mut handler = DiscoveryHandler::new(IpType::V4);
+        handler.add_service(String::from("_hap._tcp.local"));
+        handler.add_responses(packet(), 1);
+
+        assert_eq!(handler.cache.len(), 4);
+        assert_eq!(handler.get_instance_records("_hap._tcp.local").len(), 2);
+        assert_eq!(handler.refresh_records("_hap._tcp.local").len(), 4);
+        assert_eq!(handler.cache.get("gw2.local").count(), 1);
+        assert_eq!(handler.cache.get("tv.local").count(), 0);
+        assert_eq!(handler.cache.get("_airplay._tcp.local").count(), 0);
+    }
+
+    #[test]
+    fn test_add_responses_address_from_earlier_srv()
+    {
+        let mut handler = DiscoveryHandler::new(IpType::V4);
+        handler.add_service(String::from("_hap._tcp.local"));
+        handler.add_responses(packet()[..3].to_vec(), 1);
+        handler.add_responses(packet()[3..].to_vec(), 1);
+
+        assert_eq!(handler.cache.len(), 4);
+        assert_eq!(handler.cache.get("gw2.local").count(), 1);
+    }
+
+    #[test]
+    fn test_repeated_responses_are_not_stored_twice()
+    {
+        let mut handler = DiscoveryHandler::new(IpType::V4);
+        handler.add_service(String::from("_hap._tcp.local"));
+        for _ in 0..10
+        {
+            handler.add_responses(packet(), 1);
+        }
+
+        assert_eq!(handler.cache.len(), 4);
+    }
+
+    #[test]
+    fn test_goodbye_marks_instance_removed()
+    {
+        let mut handler = DiscoveryHandler::new(IpType::V4);
+        handler.add_service(String::from("_hap._tcp.local"));
+        handler.add_responses(packet(), 1);
+
+        let mut goodbyes = packet()[..2].to_vec();
+        for goodbye in goodbyes.iter_mut()
+        {
+            goodbye.set_ttl(0);
+        }
+        handler.add_responses(goodbyes.clone(), 1);
+
+        assert!(handler.is_removed("DIRIGERA._hap._tcp.local"));
+        assert_eq!(handler.cache.len(), 4);
+        assert!(handler.refresh_records("_hap._tcp.local").is_empty());
+
+        handler.add_responses(packet()[..1].to_vec(), 1);
+        assert!(!handler.is_removed("DIRIGERA._hap._tcp.local"));
+        assert_eq!(handler.get_instances("_hap._tcp.local"), vec![String::from("DIRIGERA._hap._tcp.local")]);
+
+        // Nothing is left once the goodbye records are evicted.
+        goodbyes.truncate(1);
+        handler.add_responses(goodbyes, 1);
+        assert!(handler.get_instances("_hap._tcp.local").is_empty());
+        handler.cache.remove_expired(Instant::now() + Duration::from_secs(2));
+        assert!(!handler.is_removed("DIRIGERA._hap._tcp.local"));
+        assert_eq!(handler.cache.len(), 2);
+    }
+
+    #[test]
+    fn test_questions()
+    {
+        let mut handler = DiscoveryHandler::new(IpType::DualStack);
+        handler.add_service(String::from("_hap._tcp.local"));
+        let ptr = Question { name: String::from("_hap._tcp.local"), qtype: Type::PTR };
+
+        assert_eq!(handler.questions("_hap._tcp.local", 1, Instant::now()), vec![ptr.clone()]);
+
+        handler.add_responses(packet()[..1].to_vec(), 1);
+        assert_eq!(handler.questions("_hap._tcp.local", 1, Instant::now()), vec![
+            ptr.clone(),
+            Question { name: String::from("DIRIGERA._hap._tcp.local"), qtype: Type::SRV },
+            Question { name: String::from("DIRIGERA._hap._tcp.local"), qtype: Type::TXT }
+        ]);
+
+        handler.add_responses(packet()[1..4].to_vec(), 1);
+        let now = Instant::now();
+        assert_eq!(handler.questions("_hap._tcp.local", 1, now), vec![
+            ptr.clone(),
+            Question { name: String::from("gw2.local"), qtype: Type::AAAA }
+        ]);
+
+        // Records heard on another interface don't count, and records past half of their TTL
+        // are asked for again.
+        assert_eq!(handler.questions("_hap._tcp.local", 2, now).len(), 5);
+        assert_eq!(handler.questions("_hap._tcp.local", 1, now + Duration::from_secs(61)), vec![
+            ptr,
+            Question { name: String::from("DIRIGERA._hap._tcp.local"), qtype: Type::SRV },
+            Question { name: String::from("gw2.local"), qtype: Type::A },
+            Question { name: String::from("gw2.local"), qtype: Type::AAAA }
+        ]);
+    }
+
+    #[test]
+    fn test_known_answers()
+    {
+        let mut handler = DiscoveryHandler::new(IpType::V4);
+        handler.add_service(String::from("_hap._tcp.local"));
+        handler.add_responses(packet(), 1);
+        let now = Instant::now();
+
+        let known_answers = handler.known_answers("_hap._tcp.local", 1, now);
+        assert_eq!(known_answers.len(), 1);
+        assert_eq!(known_answers[0].service, "DIRIGERA._hap._tcp.local");
+        assert!(known_answers[0].ttl > 4490 && known_answers[0].ttl <= 4500);
+
+        assert!(handler.known_answers("_hap._tcp.local", 2, now).is_empty());
+        let ttl = handler.known_answers("_hap._tcp.local", 1, now + Duration::from_secs(2000))[0].ttl;
+        assert!(ttl > 2490 && ttl <= 2500);
+        assert!(handler.known_answers("_hap._tcp.local", 1, now + Duration::from_secs(2300)).is_empty());
+    }
+
+    #[test]
+    fn test_browse_instances()
+    {
+        let mut handler = DiscoveryHandler::new(IpType::V4);
+        handler.add_service(String::from("_hap._tcp.local"));
+        handler.add_responses(packet(), 1);
+        handler.add_responses(vec![
+            DnsSdResponse::PtrAnswer(PtrAnswer { label: String::from("_hap._tcp.local"), service: String::from("Bridge._hap._tcp.local"), class: 1, ttl: 4500, cache_flush: false }),
+            DnsSdResponse::SrvAnswer(SrvAnswer { label: String::from("Bridge._hap._tcp.local"), service: String::from("bridge.local"), priority: 0, weight: 0, port: 51826, class: 1, ttl: 120, cache_flush: true }),
+            DnsSdResponse::AAnswer(AAnswer { label: String::from("bridge.local"), address: Ipv4Addr::new(192, 168, 1, 4), class: 1, ttl: 120, cache_flush: true })
+        ], 1);
+
+        assert_eq!(handler.get_instances("_hap._tcp.local"), vec!["Bridge._hap._tcp.local", "DIRIGERA._hap._tcp.local"]);
+
+        let srv_answer = handler.get_srv("DIRIGERA._hap._tcp.local").unwrap();
+        assert_eq!(srv_answer.service, "gw2.local");
+        assert_eq!(srv_answer.port, 8000);
+        assert_eq!(handler.get_addresses("gw2.local"), vec![IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2))]);
+        assert_eq!(handler.get_txt("DIRIGERA._hap._tcp.local").unwrap().records, vec!["ff=1"]);
+
+        let srv_answer = handler.get_srv("Bridge._hap._tcp.local").unwrap();
+        assert_eq!(srv_answer.service, "bridge.local");
+        assert_eq!(srv_answer.port, 51826);
+        assert_eq!(handler.get_addresses("bridge.local"), vec![IpAddr::V4(Ipv4Addr::new(192, 168, 1, 4))]);
+        assert!(handler.get_txt("Bridge._hap._tcp.local").is_none());
+
+        let mut goodbye = packet()[0].clone();
+        goodbye.set_ttl(0);
+        handler.add_responses(vec![goodbye], 1);
+        assert_eq!(handler.get_instances("_hap._tcp.local"), vec!["Bridge._hap._tcp.local"]);
+    }
+
+    #[test]
+    fn test_get_service_instances()
+    {
+        let mut handler = DiscoveryHandler::new(IpType::V4);
+        handler.add_service(String::from("_hap._tcp.local"));
+        handler.add_responses(packet(), 1);
+
+        let instances = handler.get_service_instances("_hap._tcp.local");
+        assert_eq!(instances.len(), 1);
+        assert_eq!(instances[0].name, "DIRIGERA");
+        assert_eq!(instances[0].service_type, "_hap._tcp");
+        assert_eq!(instances[0].domain, "local");
+        assert_eq!(instances[0].host, "gw2.local");
+        assert_eq!(instances[0].port, 8000);
+        assert_eq!(instances[0].addresses, vec![IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2))]);
+        assert_eq!(instances[0].txt["ff"], Some(String::from("1")));
+
+        assert_eq!(handler.get_service_of("DIRIGERA._hap._tcp.local").unwrap(), "_hap._tcp.local");
+        assert!(handler.get_service_of("TV._airplay._tcp.local").is_none());
+    }
+
+    #[test]
+    fn test_cache_all()
+    {
+        let mut handler = DiscoveryHandler::new(IpType::V4);
+        assert!(!handler.wants_responses());
+
+        handler.set_cache_all(true);
+        assert!(handler.wants_responses());
+        handler.add_responses(packet(), 1);
+        assert_eq!(handler.cache.len(), 6);
+
+        // Looking for a service afterwards finds what was already heard.
+        handler.add_service(String::from("_airplay._tcp.local"));
+        assert_eq!(handler.get_instances("_airplay._tcp.local"), vec!["TV._airplay._tcp.local"]);
+    }
+
+    #[test]
+    fn test_multiple_interfaces()
+    {
+        let mut handler = DiscoveryHandler::new(IpType::V4);
+        handler.add_service(String::from("_hap._tcp.local"));
+        handler.add_responses(packet(), 2);
+        handler.add_responses(packet(), 1);
+
+        assert_eq!(handler.cache.len(), 8);
+        assert_eq!(handler.get_instances("_hap._tcp.local"), vec!["DIRIGERA._hap._tcp.local"]);
+
+        let instances = handler.get_service_instances("_hap._tcp.local");
+        assert_eq!(instances.len(), 1);
+        assert_eq!(instances[0].addresses, vec![IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2))]);
+        assert_eq!(instances[0].interfaces, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_dual_stack_addresses()
+    {
+        let aaaa_answer = DnsSdResponse::AaaaAnswer(AaaaAnswer { label: String::from("gw2.local"), address: Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 2), class: 1, ttl: 120, cache_flush: false });
+        let ipv4 = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2));
+        let ipv6 = IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 2));
+
+        let mut handler = DiscoveryHandler::new(IpType::DualStack);
+        handler.add_service(String::from("_hap._tcp.local"));
+        handler.add_responses(packet(), 1);
+        handler.add_responses(vec![aaaa_answer.clone()], 1);
+
+        assert_eq!(handler.get_addresses("gw2.local"), vec![ipv4, ipv6]);
+        handler.set_address_preference(AddressPreference::Ipv6First);
+        assert_eq!(handler.get_addresses("gw2.local"), vec![ipv6, ipv4]);
+        assert_eq!(handler.get_service_instances("_hap._tcp.local")[0].addresses, vec![ipv6, ipv4]);
+
+        let mut handler = DiscoveryHandler::new(IpType::V4);
+        handler.add_service(String::from("_hap._tcp.local"));
+        handler.add_responses(packet(), 1);
+        handler.add_responses(vec![aaaa_answer], 1);
+
+        assert_eq!(handler.get_addresses("gw2.local"), vec![ipv4]);
+    }
+
+    #[test]
+    fn test_collect_events()
+    {
+        let mut handler = DiscoveryHandler::new(IpType::V4);
+        handler.add_service(String::from("_hap._tcp.local"));
+        assert!(handler.collect_events().is_empty());
+
+        handler.add_responses(packet()[..1].to_vec(), 1);
+        let events = handler.collect_events();
+        assert_eq!(events, vec![ServiceEvent::Found { service: String::from("_hap._tcp.local"), instance: String::from("DIRIGERA._hap._tcp.local") }]);
+
+        handler.add_responses(packet()[1..].to_vec(), 1);
+        let events = handler.collect_events();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(&events[0], ServiceEvent::Resolved(instance) if instance.port == 8000));
+        assert!(handler.collect_events().is_empty());
+
+        handler.add_responses(vec![DnsSdResponse::AAnswer(AAnswer { label: String::from("gw2.local"), address: Ipv4Addr::new(192, 168, 1, 5), class: 1, ttl: 120, cache_flush: false })], 1);
+        let events = handler.collect_events();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(&events[0], ServiceEvent::Updated(instance) if instance.addresses.len() == 2));
+
+        let mut goodbye = packet()[0].clone();
+        goodbye.set_ttl(0);
+        handler.add_responses(vec![goodbye], 1);
+        let events = handler.collect_events();
+        assert_eq!(events, vec![ServiceEvent::Removed { service: String::from("_hap._tcp.local"), instance: String::from("DIRIGERA._hap._tcp.local") }]);
+    }
+
+    #[test]
+    fn test_is_instance_of()
+    {
+        assert!(is_instance_of("DIRIGERA._hap._tcp.local", "_hap._tcp.local"));
+        assert!(is_instance_of("dirigera._HAP._tcp.local", "_hap._tcp.local"));
+        assert!(!is_instance_of("_hap._tcp.local", "_hap._tcp.local"));
+        assert!(!is_instance_of("DIRIGERA_hap._tcp.local", "_hap._tcp.local"));
     }
 }
@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::fmt::Display;
 use std::net::{ Ipv4Addr, Ipv6Addr };
 
@@ -5,48 +6,122 @@ use crate::dnssd_error::DnsSdError;
 
 const FLAGS_QR_MASK: u16 = 0x8000;
 const FLAGS_QR_RESPONSE: u16 = 0x8000;
+const FLAGS_AA: u16 = 0x0400;
+const FLAGS_TC: u16 = 0x0200;
+
+const CLASS_CACHE_FLUSH_MASK: u16 = 0x8000;
+const CLASS_UNICAST_RESPONSE_MASK: u16 = 0x8000;
+
+const CLASS_IN: u16 = 0x0001;
 
 const MAX_COMPRESSION_POINTERS: u8 = 126;
 const MAX_LABEL_OCTETS: u8 = 255;
+const MAX_LABEL_LEN: usize = 63;
+
+const DNS_HEADER_SIZE: usize = 12;
+// Largest UDP payload that fits an Ethernet frame over IPv6 without fragmenting.
+const MAX_QUERY_PACKET_SIZE: usize = 1452;
+// Largest mDNS message, RFC 6762 section 17.
+const MAX_PACKET_SIZE: usize = 9000;
 
+#[derive(Clone)]
+#[allow(clippy::enum_variant_names)]
 pub enum DnsSdResponse
 {
     PtrAnswer(PtrAnswer),
     SrvAnswer(SrvAnswer),
     TxtAnswer(TxtAnswer),
     AAnswer(AAnswer),
-    AaaaAnswer(AaaaAnswer)
+    AaaaAnswer(AaaaAnswer),
+    UnknownAnswer(UnknownAnswer)
 }
 
+#[derive(Clone)]
 pub struct PtrAnswer
 {
     pub label: String,
-    pub service: String
+    pub service: String,
+    pub class: u16,
+    pub ttl: u32,
+    pub cache_flush: bool
 }
 
+#[derive(Clone)]
 pub struct SrvAnswer
 {
     pub label: String,
     pub service: String,
-    pub port: u16
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub class: u16,
+    pub ttl: u32,
+    pub cache_flush: bool
 }
 
+#[derive(Clone)]
 pub struct TxtAnswer
 {
     pub label: String,
-    pub records: Vec<String>
+    pub records: Vec<String>,
+    pub class: u16,
+    pub ttl: u32,
+    pub cache_flush: bool
 }
 
+#[derive(Clone)]
 pub struct AAnswer
 {
     pub label: String,
-    pub address: Ipv4Addr
+    pub address: Ipv4Addr,
+    pub class: u16,
+    pub ttl: u32,
+    pub cache_flush: bool
 }
 
+#[derive(Clone)]
 pub struct AaaaAnswer
 {
     pub label: String,
-    pub address: Ipv6Addr
+    pub address: Ipv6Addr,
+    pub class: u16,
+    pub ttl: u32,
+    pub cache_flush: bool
+}
+
+// Record of a type we don't parse, kept with its raw type number and data. Names in the data are
+// uncompressed.
+#[derive(Clone)]
+pub struct UnknownAnswer
+{
+    pub label: String,
+    pub rtype: u16,
+    pub data: Vec<u8>,
+    pub class: u16,
+    pub ttl: u32,
+    pub cache_flush: bool
+}
+
+// A question of a query, for the records of one type of a name.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Question
+{
+    pub name: String,
+    pub qtype: Type
+}
+
+// A query received from another host.
+pub struct DnsSdQuery
+{
+    // Non-zero in queries from legacy resolvers, echoed in the response to them.
+    pub id: u16,
+    // Questions of types we don't know are left out.
+    pub questions: Vec<Question>,
+    // Whether a question has the unicast response bit set, RFC 6762 section 5.4.
+    pub unicast_response: bool,
+    pub known_answers: Vec<DnsSdResponse>,
+    // Records a probing host intends to use, RFC 6762 section 8.2.
+    pub authorities: Vec<DnsSdResponse>
 }
 
 pub struct DnsSdHeader
@@ -59,7 +134,9 @@ pub struct DnsSdHeader
     additional_len: u16
 }
 
-enum Type
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Type
 {
     A = 1,
     NS = 2,
@@ -79,22 +156,17 @@ impl DnsSdHeader
 {
     pub fn from(buffer: &[u8], count: usize) -> Result<DnsSdHeader, DnsSdError>
     {
-        if count < 12
-        {
-            return Err(DnsSdError::InvalidDnsSdHeader);
-        }
-
-        let id = u16::from_be_bytes([buffer[0], buffer[1]]);
-        if id != 0
+        if count < DNS_HEADER_SIZE || buffer.len() < DNS_HEADER_SIZE
         {
             return Err(DnsSdError::InvalidDnsSdHeader);
         }
 
-        let flags = u16::from_be_bytes([buffer[2], buffer[3]]);
-        let queries_len = u16::from_be_bytes([buffer[4], buffer[5]]);
-        let answers_len = u16::from_be_bytes([buffer[6], buffer[7]]);
-        let authorities_len = u16::from_be_bytes([buffer[8], buffer[9]]);
-        let additional_len = u16::from_be_bytes([buffer[10], buffer[11]]);
+        let id = read_u16(buffer, 0)?;
+        let flags = read_u16(buffer, 2)?;
+        let queries_len = read_u16(buffer, 4)?;
+        let answers_len = read_u16(buffer, 6)?;
+        let authorities_len = read_u16(buffer, 8)?;
+        let additional_len = read_u16(buffer, 10)?;
 
         Ok(DnsSdHeader
         {
@@ -123,13 +195,81 @@ impl DnsSdHeader
 }
 
 
+impl DnsSdQuery
+{
+    pub fn from(buffer: &[u8], count: usize) -> Result<DnsSdQuery, DnsSdError>
+    {
+        let buffer = match buffer.get(..count)
+        {
+            Some(buffer) => buffer,
+            None => return Err(DnsSdError::Truncated)
+        };
+
+        let header = DnsSdHeader::from(buffer, count)?;
+
+        if header.flags & FLAGS_QR_MASK == FLAGS_QR_RESPONSE
+        {
+            return Err(DnsSdError::NotDnsSdQuery);
+        }
+
+        let mut offset: usize = DNS_HEADER_SIZE;
+        let mut questions = Vec::new();
+        let mut unicast_response = false;
+        for _ in 0..header.queries_len
+        {
+            let (name, label_end) = DnsSdResponse::label_to_string(buffer, offset)?;
+            let qtype = read_u16(buffer, label_end)?;
+            let class = read_u16(buffer, label_end + 2)?;
+            offset = label_end + 4;
+
+            unicast_response |= class & CLASS_UNICAST_RESPONSE_MASK != 0;
+            if let Ok(qtype) = Type::from(qtype)
+            {
+                questions.push(Question { name, qtype });
+            }
+        }
+
+        let mut known_answers = Vec::new();
+        for _ in 0..header.answers_len
+        {
+            offset = DnsSdResponse::parse_record(buffer, offset, &mut known_answers)?;
+        }
+
+        let mut authorities = Vec::new();
+        for _ in 0..header.authorities_len
+        {
+            offset = DnsSdResponse::parse_record(buffer, offset, &mut authorities)?;
+        }
+
+        Ok(DnsSdQuery
+        {
+            id: header.id,
+            questions,
+            unicast_response,
+            known_answers,
+            authorities
+        })
+    }
+}
+
 impl DnsSdResponse
 {
-    pub fn from(buffer: &[u8], count: usize) -> Result<(String, Vec<DnsSdResponse>), DnsSdError>
+    pub fn from(buffer: &[u8], count: usize) -> Result<Vec<DnsSdResponse>, DnsSdError>
     {
         let mut responses: Vec<DnsSdResponse> = Vec::new();
 
+        // Never look at bytes past what was received.
+        let buffer = match buffer.get(..count)
+        {
+            Some(buffer) => buffer,
+            None => return Err(DnsSdError::Truncated)
+        };
+
         let header = DnsSdHeader::from(buffer, count)?;
+        if header.id != 0
+        {
+            return Err(DnsSdError::InvalidDnsSdHeader);
+        }
 
         if header.flags & FLAGS_QR_MASK != FLAGS_QR_RESPONSE
         {
@@ -141,27 +281,27 @@ impl DnsSdResponse
             return Err(DnsSdError::NoAnswers);
         }
 
-        let mut offset: usize = 12;
+        let mut offset: usize = DNS_HEADER_SIZE;
 
-        // Parse queries to get correct start offset for answers and to get label.
-        let mut answer_label: Option<String> = None;
+        // Skip queries to get correct start offset for answers. Multicast responses usually carry
+        // no questions at all (RFC 6762 section 6), so records are identified by their owner names.
         for _ in 0..header.queries_len
         {
-            // Parse question label.
-            let query_label = DnsSdResponse::label_to_string(buffer, offset)?;
-            answer_label = Some(query_label.0);
-            offset = query_label.1;
-            offset += 4;
+            let (_, label_end) = DnsSdResponse::label_to_string(buffer, offset)?;
+            offset = label_end + 4;
         }
 
-        if answer_label.is_none()
+        for _ in 0..header.answers_len
         {
-            return Err(DnsSdError::InvalidDnsSdResponse);
+            offset = DnsSdResponse::parse_record(buffer, offset, &mut responses)?;
         }
 
-        for _ in 0..header.answers_len
+        // Authority records only claim names, they are parsed to find where the additional records
+        // start but are not answers.
+        let mut authorities = Vec::new();
+        for _ in 0..header.authorities_len
         {
-            offset = DnsSdResponse::parse_record(buffer, offset, &mut responses)?;
+            offset = DnsSdResponse::parse_record(buffer, offset, &mut authorities)?;
         }
 
         for _ in 0..header.additional_len
@@ -169,7 +309,188 @@ impl DnsSdResponse
             offset = DnsSdResponse::parse_record(buffer, offset, &mut responses)?;
         }
 
-        return Ok((answer_label.unwrap(), responses));
+        Ok(responses)
+    }
+
+    pub fn label(&self) -> &str
+    {
+        match self
+        {
+            DnsSdResponse::PtrAnswer(answer) => &answer.label,
+            DnsSdResponse::SrvAnswer(answer) => &answer.label,
+            DnsSdResponse::TxtAnswer(answer) => &answer.label,
+            DnsSdResponse::AAnswer(answer) => &answer.label,
+            DnsSdResponse::AaaaAnswer(answer) => &answer.label,
+            DnsSdResponse::UnknownAnswer(answer) => &answer.label
+        }
+    }
+
+    pub fn ttl(&self) -> u32
+    {
+        match self
+        {
+            DnsSdResponse::PtrAnswer(answer) => answer.ttl,
+            DnsSdResponse::SrvAnswer(answer) => answer.ttl,
+            DnsSdResponse::TxtAnswer(answer) => answer.ttl,
+            DnsSdResponse::AAnswer(answer) => answer.ttl,
+            DnsSdResponse::AaaaAnswer(answer) => answer.ttl,
+            DnsSdResponse::UnknownAnswer(answer) => answer.ttl
+        }
+    }
+
+    pub fn rtype(&self) -> u16
+    {
+        match self
+        {
+            DnsSdResponse::PtrAnswer(_) => Type::PTR as u16,
+            DnsSdResponse::SrvAnswer(_) => Type::SRV as u16,
+            DnsSdResponse::TxtAnswer(_) => Type::TXT as u16,
+            DnsSdResponse::AAnswer(_) => Type::A as u16,
+            DnsSdResponse::AaaaAnswer(_) => Type::AAAA as u16,
+            DnsSdResponse::UnknownAnswer(answer) => answer.rtype
+        }
+    }
+
+    pub fn class(&self) -> u16
+    {
+        match self
+        {
+            DnsSdResponse::PtrAnswer(answer) => answer.class,
+            DnsSdResponse::SrvAnswer(answer) => answer.class,
+            DnsSdResponse::TxtAnswer(answer) => answer.class,
+            DnsSdResponse::AAnswer(answer) => answer.class,
+            DnsSdResponse::AaaaAnswer(answer) => answer.class,
+            DnsSdResponse::UnknownAnswer(answer) => answer.class
+        }
+    }
+
+    // Canonical form of the record data, used to tell records with the same name and type apart.
+    // Names are lowercased since DNS names compare case-insensitively.
+    pub fn rdata_key(&self) -> Vec<u8>
+    {
+        let mut key = Vec::new();
+        match self
+        {
+            DnsSdResponse::PtrAnswer(answer) =>
+            {
+                key.extend_from_slice(answer.service.to_ascii_lowercase().as_bytes());
+            },
+            DnsSdResponse::SrvAnswer(answer) =>
+            {
+                key.extend_from_slice(&answer.priority.to_be_bytes());
+                key.extend_from_slice(&answer.weight.to_be_bytes());
+                key.extend_from_slice(&answer.port.to_be_bytes());
+                key.extend_from_slice(answer.service.to_ascii_lowercase().as_bytes());
+            },
+            DnsSdResponse::TxtAnswer(answer) =>
+            {
+                for record in &answer.records
+                {
+                    key.extend_from_slice(&(record.len() as u32).to_be_bytes());
+                    key.extend_from_slice(record.as_bytes());
+                }
+            },
+            DnsSdResponse::AAnswer(answer) =>
+            {
+                key.extend_from_slice(&answer.address.octets());
+            },
+            DnsSdResponse::AaaaAnswer(answer) =>
+            {
+                key.extend_from_slice(&answer.address.octets());
+            },
+            DnsSdResponse::UnknownAnswer(answer) =>
+            {
+                key.extend_from_slice(&answer.data);
+            }
+        }
+
+        key
+    }
+
+    // Same name, type and data. Class, TTL and the cache flush bit are not compared.
+    pub fn is_same_record(&self, other: &DnsSdResponse) -> bool
+    {
+        self.label().eq_ignore_ascii_case(other.label()) && self.rtype() == other.rtype() && self.rdata_key() == other.rdata_key()
+    }
+
+    // Record data as sent on the wire, without name compression.
+    pub fn rdata(&self) -> Result<Vec<u8>, DnsSdError>
+    {
+        let mut rdata = Vec::new();
+        match self
+        {
+            DnsSdResponse::PtrAnswer(answer) => write_name(&mut rdata, &answer.service)?,
+            DnsSdResponse::SrvAnswer(answer) =>
+            {
+                rdata.extend_from_slice(&answer.priority.to_be_bytes());
+                rdata.extend_from_slice(&answer.weight.to_be_bytes());
+                rdata.extend_from_slice(&answer.port.to_be_bytes());
+                write_name(&mut rdata, &answer.service)?;
+            },
+            DnsSdResponse::TxtAnswer(answer) =>
+            {
+                for txt in &answer.records
+                {
+                    if txt.len() > u8::MAX as usize
+                    {
+                        return Err(DnsSdError::LabelToLong);
+                    }
+
+                    rdata.push(txt.len() as u8);
+                    rdata.extend_from_slice(txt.as_bytes());
+                }
+            },
+            DnsSdResponse::AAnswer(answer) => rdata.extend_from_slice(&answer.address.octets()),
+            DnsSdResponse::AaaaAnswer(answer) => rdata.extend_from_slice(&answer.address.octets()),
+            DnsSdResponse::UnknownAnswer(answer) => rdata.extend_from_slice(&answer.data)
+        }
+
+        Ok(rdata)
+    }
+
+    pub fn cache_flush(&self) -> bool
+    {
+        match self
+        {
+            DnsSdResponse::PtrAnswer(answer) => answer.cache_flush,
+            DnsSdResponse::SrvAnswer(answer) => answer.cache_flush,
+            DnsSdResponse::TxtAnswer(answer) => answer.cache_flush,
+            DnsSdResponse::AAnswer(answer) => answer.cache_flush,
+            DnsSdResponse::AaaaAnswer(answer) => answer.cache_flush,
+            DnsSdResponse::UnknownAnswer(answer) => answer.cache_flush
+        }
+    }
+
+    // The record as sent to a legacy resolver, with the TTL capped and without the cache flush bit.
+    pub fn for_legacy_unicast(mut self, max_ttl: u32) -> DnsSdResponse
+    {
+        let (ttl, cache_flush) = match &mut self
+        {
+            DnsSdResponse::PtrAnswer(answer) => (&mut answer.ttl, &mut answer.cache_flush),
+            DnsSdResponse::SrvAnswer(answer) => (&mut answer.ttl, &mut answer.cache_flush),
+            DnsSdResponse::TxtAnswer(answer) => (&mut answer.ttl, &mut answer.cache_flush),
+            DnsSdResponse::AAnswer(answer) => (&mut answer.ttl, &mut answer.cache_flush),
+            DnsSdResponse::AaaaAnswer(answer) => (&mut answer.ttl, &mut answer.cache_flush),
+            DnsSdResponse::UnknownAnswer(answer) => (&mut answer.ttl, &mut answer.cache_flush)
+        };
+
+        *ttl = (*ttl).min(max_ttl);
+        *cache_flush = false;
+
+        self
+    }
+
+    pub fn set_ttl(&mut self, ttl: u32)
+    {
+        match self
+        {
+            DnsSdResponse::PtrAnswer(answer) => answer.ttl = ttl,
+            DnsSdResponse::SrvAnswer(answer) => answer.ttl = ttl,
+            DnsSdResponse::TxtAnswer(answer) => answer.ttl = ttl,
+            DnsSdResponse::AAnswer(answer) => answer.ttl = ttl,
+            DnsSdResponse::AaaaAnswer(answer) => answer.ttl = ttl,
+            DnsSdResponse::UnknownAnswer(answer) => answer.ttl = ttl
+        }
     }
 
     fn parse_record(buffer: &[u8], mut offset: usize, responses: &mut Vec<DnsSdResponse>) -> Result<usize, DnsSdError>
@@ -178,48 +499,43 @@ impl DnsSdResponse
         let (label, label_end) = DnsSdResponse::label_to_string(buffer, offset)?;
         offset = label_end;
 
-        let answer_type = Type::from(u16::from_be_bytes([buffer[offset], buffer[offset + 1]]))?;
-        let answer_data_len = u16::from_be_bytes([buffer[offset + 8], buffer[offset + 9]]);
+        let rtype = read_u16(buffer, offset)?;
+        let class = read_u16(buffer, offset + 2)?;
+        let cache_flush = class & CLASS_CACHE_FLUSH_MASK != 0;
+        let class = class & !CLASS_CACHE_FLUSH_MASK;
+        let ttl = read_u32(buffer, offset + 4)?;
+        let answer_data_len = read_u16(buffer, offset + 8)? as usize;
         offset += 10;
 
-        match answer_type
+        // All record data must be inside the packet, the parsing below never reads past it.
+        let data = read_slice(buffer, offset, answer_data_len)?;
+        let end = offset + answer_data_len;
+
+        match Type::from(rtype)
         {
-            Type::A =>
+            Ok(Type::A) =>
             {
                 // We got an IPv4 address. Parse and return it.
-                if answer_data_len != 4
+                let octets: [u8; 4] = match data.try_into()
                 {
-                    return Err(DnsSdError::InvalidDnsSdResponse);
-                }
+                    Ok(octets) => octets,
+                    Err(_) => return Err(DnsSdError::InvalidDnsSdResponse)
+                };
 
-                let data = Ipv4Addr::new(buffer[offset], buffer[offset + 1], buffer[offset + 2], buffer[offset + 3]);
-                offset += 4;
-
-                responses.push(DnsSdResponse::AAnswer(AAnswer { label, address: data }));
+                responses.push(DnsSdResponse::AAnswer(AAnswer { label, address: Ipv4Addr::from(octets), class, ttl, cache_flush }));
             },
-            Type::AAAA =>
+            Ok(Type::AAAA) =>
             {
                 // We got an IPv6 address. Parse and return it.
-                if answer_data_len != 16
+                let octets: [u8; 16] = match data.try_into()
                 {
-                    return Err(DnsSdError::InvalidDnsSdResponse);
-                }
+                    Ok(octets) => octets,
+                    Err(_) => return Err(DnsSdError::InvalidDnsSdResponse)
+                };
 
-                let data = Ipv6Addr::new(
-                    u16::from_be_bytes([buffer[offset], buffer[offset + 1]]),
-                    u16::from_be_bytes([buffer[offset + 2], buffer[offset + 3]]),
-                    u16::from_be_bytes([buffer[offset + 4], buffer[offset + 5]]),
-                    u16::from_be_bytes([buffer[offset + 6], buffer[offset + 7]]),
-                    u16::from_be_bytes([buffer[offset + 8], buffer[offset + 9]]),
-                    u16::from_be_bytes([buffer[offset + 10], buffer[offset + 11]]),
-                    u16::from_be_bytes([buffer[offset + 12], buffer[offset + 13]]),
-                    u16::from_be_bytes([buffer[offset + 14], buffer[offset + 15]])
-                );
-                offset += 16;
-
-                responses.push(DnsSdResponse::AaaaAnswer(AaaaAnswer { label, address: data }));
+                responses.push(DnsSdResponse::AaaaAnswer(AaaaAnswer { label, address: Ipv6Addr::from(octets), class, ttl, cache_flush }));
             },
-            Type::SRV =>
+            Ok(Type::SRV) =>
             {
                 // We got a service record. Parse and return it.
                 if answer_data_len < 6
@@ -227,188 +543,609 @@ impl DnsSdResponse
                     return Err(DnsSdError::InvalidDnsSdResponse);
                 }
 
-                let port = u16::from_be_bytes([buffer[offset + 4], buffer[offset + 5]]);
-                offset += 6;
+                let priority = read_u16(buffer, offset)?;
+                let weight = read_u16(buffer, offset + 2)?;
+                let port = read_u16(buffer, offset + 4)?;
 
                 // Parse DNS target.
-                let (service, label_end) = DnsSdResponse::label_to_string(buffer, offset)?;
-                offset = label_end;
+                let (service, label_end) = DnsSdResponse::label_to_string(buffer, offset + 6)?;
+                if label_end > end
+                {
+                    return Err(DnsSdError::InvalidDnsSdResponse);
+                }
 
-                responses.push(DnsSdResponse::SrvAnswer(SrvAnswer { label, service, port }));
+                responses.push(DnsSdResponse::SrvAnswer(SrvAnswer { label, service, priority, weight, port, class, ttl, cache_flush }));
             },
-            Type::PTR =>
+            Ok(Type::PTR) =>
             {
                 // We got a PTR record. Parse and return it.
                 let (service, label_end) = DnsSdResponse::label_to_string(buffer, offset)?;
-                offset = label_end;
+                if label_end > end
+                {
+                    return Err(DnsSdError::InvalidDnsSdResponse);
+                }
+
+                responses.push(DnsSdResponse::PtrAnswer(PtrAnswer { label, service, class, ttl, cache_flush }));
+            },
+            Ok(Type::TXT) =>
+            {
+                // We got a TXT record. Parse and return it.
+                let mut records: Vec<String> = Vec::new();
+
+                let mut txt_offset = 0;
+                while txt_offset < data.len()
+                {
+                    let txt_len = data[txt_offset] as usize;
+                    txt_offset += 1;
+
+                    let txt = match data.get(txt_offset..txt_offset + txt_len)
+                    {
+                        Some(txt) => txt,
+                        None => return Err(DnsSdError::InvalidDnsSdResponse)
+                    };
+
+                    let txt = match std::str::from_utf8(txt)
+                    {
+                        Ok(s) => s,
+                        Err(_) => return Err(DnsSdError::InvalidUtf8)
+                    };
+
+                    records.push(txt.to_string());
+
+                    txt_offset += txt_len;
+                }
+
+                responses.push(DnsSdResponse::TxtAnswer(TxtAnswer { label, records, class, ttl, cache_flush }));
+            },
+            _ =>
+            {
+                // We got a record type we don't parse. Keep its data so the rest of the packet can
+                // still be used.
+                let data = uncompressed_rdata(buffer, offset, answer_data_len, rtype)?;
+                responses.push(DnsSdResponse::UnknownAnswer(UnknownAnswer { label, rtype, data, class, ttl, cache_flush }));
+            }
+        }
+
+        Ok(end)
+    }
+
+    pub(crate) fn label_to_string(buffer: &[u8], start_offset: usize) -> Result<(String, usize), DnsSdError>
+    {
+        let (labels, end_offset) = read_labels(buffer, start_offset)?;
+
+        let mut name = String::new();
+        for label in labels
+        {
+            if !name.is_empty()
+            {
+                name += ".";
+            }
+
+            name += match std::str::from_utf8(label)
+            {
+                Ok(s) => s,
+                Err(_) => return Err(DnsSdError::InvalidUtf8)
+            };
+        }
+
+        Ok((name, end_offset))
+    }
+}
+
+// Labels of a possibly compressed name and the offset right after it.
+fn read_labels(buffer: &[u8], start_offset: usize) -> Result<(Vec<&[u8]>, usize), DnsSdError>
+{
+    let mut labels = Vec::new();
+    let mut name_len = 0;
+    let mut offset = start_offset;
+    let mut ptr_budget = MAX_COMPRESSION_POINTERS;
+    let mut ptr_taken = false;
+    let mut end_offset: usize = start_offset;
+
+    loop
+    {
+        let length = read_u8(buffer, offset)?;
+        match length & 0xc0
+        {
+            0x00 =>
+            {
+                if length == 0x00
+                {
+                    // End of name, set offset to next thing.
+                    if !ptr_taken
+                    {
+                        end_offset = offset + 1;
+                    }
+
+                    break;
+                }
+
+                let label_len = length as usize;
+                offset += 1;
+
+                if label_len + 1 > MAX_LABEL_OCTETS as usize
+                {
+                    return Err(DnsSdError::LabelToLong);
+                }
+
+                let label = read_slice(buffer, offset, label_len)?;
+
+                // Length of the name written with dots.
+                if name_len + label_len + 1 > MAX_LABEL_OCTETS as usize
+                {
+                    return Err(DnsSdError::LabelToLong);
+                }
+
+                if !labels.is_empty()
+                {
+                    name_len += 1;
+                }
+                name_len += label_len;
+
+                labels.push(label);
+                offset += label_len;
+            }
+            0xc0 =>
+            {
+                // Pointer.
+                if ptr_budget == 0
+                {
+                    return Err(DnsSdError::LabelCompressionLoop);
+                }
+
+                ptr_budget -= 1;
+
+                let ptr = (read_u16(buffer, offset)? ^ 0xc000) as usize;
+                if ptr >= offset
+                {
+                    return Err(DnsSdError::LabelPtrForward);
+                }
+
+                if !ptr_taken
+                {
+                    end_offset = offset + 2;
+                    ptr_taken = true;
+                }
+
+                offset = ptr;
+            },
+            _ =>
+            {
+                return Err(DnsSdError::LabelInvalid);
+            }
+        }
+    }
+
+    Ok((labels, end_offset))
+}
+
+// Data of a record of a type we don't parse, with the names in it uncompressed. Compression
+// pointers are offsets into the packet, so they would point elsewhere once the data is sent in
+// another one. Only the types known to carry names can be handled, others are kept as they are.
+pub(crate) fn uncompressed_rdata(buffer: &[u8], offset: usize, len: usize, rtype: u16) -> Result<Vec<u8>, DnsSdError>
+{
+    // Bytes before the names and the number of names, anything after them is copied.
+    let (prefix_len, names) = match Type::from(rtype)
+    {
+        Ok(Type::NS | Type::CNAME | Type::NSEC) => (0, 1),
+        Ok(Type::MX) => (2, 1),
+        Ok(Type::SOA) => (0, 2),
+        _ => return Ok(read_slice(buffer, offset, len)?.to_vec())
+    };
+
+    let end = offset + len;
+    if prefix_len > len
+    {
+        return Err(DnsSdError::InvalidDnsSdResponse);
+    }
+
+    let mut rdata = read_slice(buffer, offset, prefix_len)?.to_vec();
+    let mut name_offset = offset + prefix_len;
+    for _ in 0..names
+    {
+        let (labels, name_end) = read_labels(buffer, name_offset)?;
+        if name_end > end
+        {
+            return Err(DnsSdError::InvalidDnsSdResponse);
+        }
+
+        for label in labels
+        {
+            rdata.push(label.len() as u8);
+            rdata.extend_from_slice(label);
+        }
+        rdata.push(0x00);
+        name_offset = name_end;
+    }
+
+    rdata.extend_from_slice(read_slice(buffer, name_offset, end - name_offset)?);
+    Ok(rdata)
+}
+
+fn read_slice(buffer: &[u8], offset: usize, len: usize) -> Result<&[u8], DnsSdError>
+{
+    match buffer.get(offset..offset + len)
+    {
+        Some(slice) => Ok(slice),
+        None => Err(DnsSdError::Truncated)
+    }
+}
+
+fn read_u8(buffer: &[u8], offset: usize) -> Result<u8, DnsSdError>
+{
+    Ok(read_slice(buffer, offset, 1)?[0])
+}
+
+fn read_u16(buffer: &[u8], offset: usize) -> Result<u16, DnsSdError>
+{
+    let bytes = read_slice(buffer, offset, 2)?;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(buffer: &[u8], offset: usize) -> Result<u32, DnsSdError>
+{
+    let bytes = read_slice(buffer, offset, 4)?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+// A DNS message to send. Names are compressed against the names written before them, RFC 1035
+// section 4.1.4.
+#[derive(Clone)]
+pub struct Message
+{
+    id: u16,
+    flags: u16,
+    max_size: usize,
+    // Questions with whether a unicast response is asked for, RFC 6762 section 5.4.
+    questions: Vec<(Question, bool)>,
+    answers: Vec<DnsSdResponse>,
+    authorities: Vec<DnsSdResponse>,
+    additional: Vec<DnsSdResponse>
+}
+
+impl Message
+{
+    pub fn query() -> Message
+    {
+        Message::new(0, 0x0000)
+    }
+
+    // The id is only non-zero in responses to legacy resolvers.
+    pub fn response(id: u16) -> Message
+    {
+        Message::new(id, FLAGS_QR_RESPONSE | FLAGS_AA)
+    }
+
+    fn new(id: u16, flags: u16) -> Message
+    {
+        Message
+        {
+            id,
+            flags,
+            max_size: MAX_PACKET_SIZE,
+            questions: Vec::new(),
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            additional: Vec::new()
+        }
+    }
+
+    // Lower the size limit, e.g. to what fits in one Ethernet frame.
+    pub fn set_max_size(&mut self, max_size: usize)
+    {
+        self.max_size = max_size.min(MAX_PACKET_SIZE);
+    }
+
+    // More known answers follow in the next packet, RFC 6762 section 7.2.
+    pub fn set_truncated(&mut self)
+    {
+        self.flags |= FLAGS_TC;
+    }
+
+    pub fn add_question(&mut self, question: Question, unicast_response: bool)
+    {
+        self.questions.push((question, unicast_response));
+    }
+
+    pub fn add_answer(&mut self, record: DnsSdResponse)
+    {
+        self.answers.push(record);
+    }
+
+    pub fn add_authority(&mut self, record: DnsSdResponse)
+    {
+        self.authorities.push(record);
+    }
+
+    pub fn add_additional(&mut self, record: DnsSdResponse)
+    {
+        self.additional.push(record);
+    }
+
+    // The message as sent, or PacketTooLarge if it is over the size limit.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, DnsSdError>
+    {
+        let buffer = self.encode()?;
+        if buffer.len() > self.max_size
+        {
+            return Err(DnsSdError::PacketTooLarge);
+        }
+
+        Ok(buffer)
+    }
+
+    fn overflows(&self) -> Result<bool, DnsSdError>
+    {
+        Ok(self.encode()?.len() > self.max_size)
+    }
+
+    fn encode(&self) -> Result<Vec<u8>, DnsSdError>
+    {
+        let count = |len: usize| u16::try_from(len).map_err(|_| DnsSdError::PacketTooLarge);
+        let header = DnsSdHeader
+        {
+            id: self.id,
+            flags: self.flags,
+            queries_len: count(self.questions.len())?,
+            answers_len: count(self.answers.len())?,
+            authorities_len: count(self.authorities.len())?,
+            additional_len: count(self.additional.len())?
+        };
+
+        let mut encoder = Encoder { buffer: header.to_bytes(), names: HashMap::new() };
+        for (question, unicast_response) in &self.questions
+        {
+            if question.name.is_empty()
+            {
+                return Err(DnsSdError::LabelInvalid);
+            }
+
+            let mut class = CLASS_IN;
+            if *unicast_response
+            {
+                class |= CLASS_UNICAST_RESPONSE_MASK;
+            }
+
+            encoder.write_name(&question.name)?;
+            encoder.buffer.extend_from_slice(&(question.qtype as u16).to_be_bytes());
+            encoder.buffer.extend_from_slice(&class.to_be_bytes());
+        }
+
+        // The cache flush bit is only sent in the answers and additional records of responses,
+        // RFC 6762 section 10.2.
+        let response = self.flags & FLAGS_QR_MASK == FLAGS_QR_RESPONSE;
+        for record in &self.answers
+        {
+            encoder.write_record(record, response && record.cache_flush())?;
+        }
 
-                responses.push(DnsSdResponse::PtrAnswer(PtrAnswer { label, service }));
-            },
-            Type::TXT =>
-            {
-                // We got a TXT record. Parse and return it.
-                let mut records: Vec<String> = Vec::new();
+        for record in &self.authorities
+        {
+            encoder.write_record(record, false)?;
+        }
 
-                let end = offset + answer_data_len as usize;
-                while offset < end
-                {
-                    let txt_len = buffer[offset] as usize;
-                    offset += 1;
+        for record in &self.additional
+        {
+            encoder.write_record(record, response && record.cache_flush())?;
+        }
 
-                    if offset + txt_len > end
-                    {
-                        return Err(DnsSdError::InvalidDnsSdResponse);
-                    }
+        Ok(encoder.buffer)
+    }
+}
 
-                    let txt = match std::str::from_utf8(&buffer[offset..offset + txt_len])
-                    {
-                        Ok(s) => s,
-                        Err(_) => return Err(DnsSdError::InvalidUtf8)
-                    };
+// Writes a message, remembering where every name and name suffix starts so later names can point
+// to them.
+struct Encoder
+{
+    buffer: Vec<u8>,
+    names: HashMap<String, usize>
+}
 
-                    records.push(txt.to_string());
+impl Encoder
+{
+    fn write_record(&mut self, record: &DnsSdResponse, cache_flush: bool) -> Result<(), DnsSdError>
+    {
+        let mut class = record.class() & !CLASS_CACHE_FLUSH_MASK;
+        if cache_flush
+        {
+            class |= CLASS_CACHE_FLUSH_MASK;
+        }
 
-                    offset += txt_len;
-                }
+        self.write_name(record.label())?;
+        self.buffer.extend_from_slice(&record.rtype().to_be_bytes());
+        self.buffer.extend_from_slice(&class.to_be_bytes());
+        self.buffer.extend_from_slice(&record.ttl().to_be_bytes());
 
-                responses.push(DnsSdResponse::TxtAnswer(TxtAnswer { label, records }));
-            },
-            _ =>
+        // The data length is filled in once the data is written, names in it may be compressed.
+        let length_offset = self.buffer.len();
+        self.buffer.extend_from_slice(&[0x00, 0x00]);
+
+        match record
+        {
+            DnsSdResponse::PtrAnswer(answer) => self.write_name(&answer.service)?,
+            DnsSdResponse::SrvAnswer(answer) =>
             {
-                // We got an answer we don't care about. Skip it.
-                offset += answer_data_len as usize;
-            }
+                self.buffer.extend_from_slice(&answer.priority.to_be_bytes());
+                self.buffer.extend_from_slice(&answer.weight.to_be_bytes());
+                self.buffer.extend_from_slice(&answer.port.to_be_bytes());
+                self.write_name(&answer.service)?;
+            },
+            _ => self.buffer.extend(record.rdata()?)
         }
 
-        Ok(offset)
+        let length = u16::try_from(self.buffer.len() - length_offset - 2).map_err(|_| DnsSdError::PacketTooLarge)?;
+        self.buffer[length_offset..length_offset + 2].copy_from_slice(&length.to_be_bytes());
+
+        Ok(())
     }
 
-    fn label_to_string(buffer: &[u8], start_offset: usize) -> Result<(String, usize), DnsSdError>
+    // Write the labels of a name until the rest of it was written before, then point there.
+    // Suffixes are matched case-sensitively so names read back exactly as given.
+    fn write_name(&mut self, name: &str) -> Result<(), DnsSdError>
     {
-        let mut name = String::new();
-        let mut offset = start_offset;
-        let mut ptr_budget = MAX_COMPRESSION_POINTERS;
-        let mut ptr_taken = false;
-        let mut end_offset: usize = start_offset;
-
-        loop
+        let labels = name_labels(name)?;
+        for i in 0..labels.len()
         {
-            match buffer[offset] & 0xc0
+            let suffix = labels[i..].join(".");
+            if let Some(offset) = self.names.get(&suffix)
             {
-                0x00 =>
-                {
-                    if buffer[offset] == 0x00
-                    {
-                        // End of name, set offset to next thing.
-                        if !ptr_taken
-                        {
-                            end_offset = offset + 1;
-                        }
+                self.buffer.extend_from_slice(&(0xc000 | *offset as u16).to_be_bytes());
+                return Ok(());
+            }
 
-                        break;
-                    }
+            // A pointer has 14 bits for the offset.
+            if self.buffer.len() < 0x4000
+            {
+                self.names.insert(suffix, self.buffer.len());
+            }
 
-                    let label_len = buffer[offset] as usize;
-                    offset += 1;
+            self.buffer.push(labels[i].len() as u8);
+            self.buffer.extend_from_slice(labels[i].as_bytes());
+        }
+        self.buffer.push(0x00);
 
-                    if offset + label_len >= buffer.len()
-                    {
-                        return Err(DnsSdError::LabelToLong);
-                    }
+        Ok(())
+    }
+}
 
-                    if label_len + 1 > MAX_LABEL_OCTETS as usize
-                    {
-                        return Err(DnsSdError::LabelToLong);
-                    }
+// Query with the given questions, with the QU bit set if unicast responses are asked for, listing
+// the PTR records already known so responders don't send them again (RFC 6762 section 7.1).
+// Questions that don't fit in one packet go in a query of their own. Known answers that don't fit
+// follow in packets without questions, every packet but the last with the TC bit set.
+pub fn new_queries(questions: &[Question], known_answers: &[PtrAnswer], unicast_response: bool) -> Result<Vec<Vec<u8>>, DnsSdError>
+{
+    if questions.is_empty()
+    {
+        return Err(DnsSdError::LabelInvalid);
+    }
 
-                    name += match std::str::from_utf8(&buffer[offset..offset + label_len])
-                    {
-                        Ok(s) => s,
-                        Err(_) => return Err(DnsSdError::InvalidUtf8)
-                    };
+    let new_query = ||
+    {
+        let mut query = Message::query();
+        query.set_max_size(MAX_QUERY_PACKET_SIZE);
+        query
+    };
 
-                    offset += label_len;
-                    if buffer[offset] != 0x00
-                    {
-                        name += ".";
-                    }
-                }
-                0xc0 =>
-                {
-                    // Pointer.
-                    if ptr_budget == 0
-                    {
-                        return Err(DnsSdError::LabelCompressionLoop);
-                    }
+    let mut packets = Vec::new();
+    let mut query = new_query();
 
-                    ptr_budget -= 1;
+    for question in questions
+    {
+        query.add_question(question.clone(), unicast_response);
 
-                    let ptr = (u16::from_be_bytes([buffer[offset], buffer[offset + 1]]) ^ 0xc000) as usize;
-                    if ptr >= offset
-                    {
-                        return Err(DnsSdError::LabelPtrForward);
-                    }
+        if query.questions.len() > 1 && query.overflows()?
+        {
+            query.questions.pop();
+            packets.push(query.to_bytes()?);
+            query = new_query();
+            query.add_question(question.clone(), unicast_response);
+        }
+    }
 
-                    if !ptr_taken
-                    {
-                        end_offset = offset + 2;
-                        ptr_taken = true;
-                    }
+    for known_answer in known_answers
+    {
+        // Known answers are shared records, sent with the TTL they have left.
+        let record = DnsSdResponse::PtrAnswer(known_answer.clone());
+        query.add_answer(record.clone());
 
-                    offset = ptr;
-                },
-                _ =>
-                {
-                    return Err(DnsSdError::LabelInvalid);
-                }
-            }
+        if query.questions.len() + query.answers.len() > 1 && query.overflows()?
+        {
+            query.answers.pop();
+            query.set_truncated();
+            packets.push(query.to_bytes()?);
+            query = new_query();
+            query.add_answer(record);
         }
-
-        Ok((name, end_offset))
     }
+
+    packets.push(query.to_bytes()?);
+
+    Ok(packets)
 }
 
-pub fn new_query(service: &str) -> Result<Vec<u8>, DnsSdError>
+// Response with the given answers and additional records. Questions are only repeated in
+// responses to legacy resolvers, RFC 6762 section 6.7.
+pub fn new_response(id: u16, questions: &[Question], answers: &[DnsSdResponse], additional: &[DnsSdResponse]) -> Result<Vec<u8>, DnsSdError>
 {
-    if service.len() == 0
+    let mut response = Message::response(id);
+    for question in questions
     {
-        return Err(DnsSdError::LabelInvalid);
+        response.add_question(question.clone(), false);
     }
 
-    let mut buffer = Vec::new();
+    for record in answers
+    {
+        response.add_answer(record.clone());
+    }
 
-    let header = DnsSdHeader
+    for record in additional
     {
-        id: 0,
-        flags: 0x0000,
-        queries_len: 1,
-        answers_len: 0,
-        authorities_len: 0,
-        additional_len: 0
-    };
+        response.add_additional(record.clone());
+    }
 
-    buffer.extend(header.to_bytes());
+    response.to_bytes()
+}
 
-    let labels: Vec<&str> = service.split(".").collect();
-    for label in labels
+// Probe for names we want to own: a question of type ANY for each name and the records we intend to
+// use in the authority section (RFC 6762 section 8.1). Responses to probes are multicast, so every
+// host probing the name hears about a conflict.
+pub fn new_probe(names: &[String], records: &[DnsSdResponse]) -> Result<Vec<u8>, DnsSdError>
+{
+    let mut probe = Message::query();
+    for name in names
     {
-        if label.len() > MAX_LABEL_OCTETS as usize
-        {
-            return Err(DnsSdError::LabelToLong);
-        }
+        probe.add_question(Question { name: name.clone(), qtype: Type::ANY }, false);
+    }
+
+    for record in records
+    {
+        probe.add_authority(record.clone());
+    }
+
+    probe.to_bytes()
+}
 
+fn write_name(buffer: &mut Vec<u8>, name: &str) -> Result<(), DnsSdError>
+{
+    for label in name_labels(name)?
+    {
         buffer.push(label.len() as u8);
         buffer.extend_from_slice(label.as_bytes());
     }
     buffer.push(0x00);
 
-    // Query type ANY
-    buffer.extend_from_slice(&0x00ff_u16.to_be_bytes());
+    Ok(())
+}
+
+// Labels of a name within the limits of RFC 1035 section 2.3.4. The empty name is the root.
+fn name_labels(name: &str) -> Result<Vec<&str>, DnsSdError>
+{
+    if name.is_empty()
+    {
+        return Ok(Vec::new());
+    }
+
+    // On the wire every label has a length octet and the name ends with a zero octet.
+    if name.len() + 2 > MAX_LABEL_OCTETS as usize
+    {
+        return Err(DnsSdError::LabelToLong);
+    }
+
+    let labels: Vec<&str> = name.split('.').collect();
+    for label in &labels
+    {
+        if label.is_empty()
+        {
+            return Err(DnsSdError::LabelInvalid);
+        }
 
-    // Query class IN
-    buffer.extend_from_slice(&0x8001_u16.to_be_bytes());
+        if label.len() > MAX_LABEL_LEN
+        {
+            return Err(DnsSdError::LabelToLong);
+        }
+    }
 
-    Ok(buffer)
+    Ok(labels)
 }
 
 impl Type
@@ -466,7 +1203,8 @@ impl std::fmt::Debug for DnsSdResponse
             DnsSdResponse::SrvAnswer(answer) => write!(f, "SRV: {} -> {}:{}", answer.label, answer.service, answer.port),
             DnsSdResponse::TxtAnswer(answer) => write!(f, "TXT: {} -> {:?}", answer.label, answer.records),
             DnsSdResponse::AAnswer(answer) => write!(f, "A: {} -> {}", answer.label, answer.address),
-            DnsSdResponse::AaaaAnswer(answer) => write!(f, "AAAA: {} -> {}", answer.label, answer.address)
+            DnsSdResponse::AaaaAnswer(answer) => write!(f, "AAAA: {} -> {}", answer.label, answer.address),
+            DnsSdResponse::UnknownAnswer(answer) => write!(f, "TYPE{}: {} -> {:02x?}", answer.rtype, answer.label, answer.data)
         }
     }
 }
@@ -564,11 +1302,18 @@ mod tests
         assert_eq!(buffer[11], 0x00);
     }
 
+    fn question(name: &str, qtype: Type) -> Question
+    {
+        Question { name: String::from(name), qtype }
+    }
+
     #[test]
     fn test_new_query()
     {
-        let query = new_query("_hap._tcp.local").unwrap();
+        let queries = new_queries(&[question("_hap._tcp.local", Type::PTR)], &[], true).unwrap();
+        assert_eq!(queries.len(), 1);
 
+        let query = &queries[0];
         assert_eq!(query.len(), 33);
         assert_eq!(query[0], 0x00);
         assert_eq!(query[1], 0x00);
@@ -600,11 +1345,242 @@ mod tests
         assert_eq!(query[27], 0x6c);
         assert_eq!(query[28], 0x00);
         assert_eq!(query[29], 0x00);
-        assert_eq!(query[30], 0xff);
+        assert_eq!(query[30], 0x0c);
         assert_eq!(query[31], 0x80);
         assert_eq!(query[32], 0x01);
     }
 
+    fn known_answer(instance: usize) -> PtrAnswer
+    {
+        PtrAnswer
+        {
+            label: String::from("_hap._tcp.local"),
+            service: format!("Accessory {}._hap._tcp.local", instance),
+            class: 1,
+            ttl: 4500,
+            cache_flush: false
+        }
+    }
+
+    #[test]
+    fn test_new_query_several_questions()
+    {
+        let questions = vec![
+            question("DIRIGERA._hap._tcp.local", Type::SRV),
+            question("DIRIGERA._hap._tcp.local", Type::TXT),
+            question("gw2.local", Type::A),
+            question("gw2.local", Type::AAAA),
+            question("gw2.local", Type::NSEC)
+        ];
+        let queries = new_queries(&questions, &[], true).unwrap();
+        assert_eq!(queries.len(), 1);
+
+        let query = &queries[0];
+        let header = DnsSdHeader::from(query, query.len()).unwrap();
+        assert_eq!(header.queries_len, 5);
+        assert_eq!(header.answers_len, 0);
+
+        // Name of 26 bytes after the header, then type SRV and class IN with the unicast bit. The
+        // same name again is a pointer to it, "gw2.local" points to "local" in it.
+        assert_eq!(query[38..42], [0x00, 0x21, 0x80, 0x01]);
+        assert_eq!(query[42..48], [0xc0, 0x0c, 0x00, 0x10, 0x80, 0x01]);
+        assert_eq!(query[48..58], [0x03, b'g', b'w', b'2', 0xc0, 0x1f, 0x00, 0x01, 0x80, 0x01]);
+        assert_eq!(query[58..64], [0xc0, 0x30, 0x00, 0x1c, 0x80, 0x01]);
+        assert_eq!(query[64..70], [0xc0, 0x30, 0x00, 0x2f, 0x80, 0x01]);
+        assert_eq!(query.len(), 70);
+
+        assert_eq!(new_queries(&[], &[], true), Err(DnsSdError::LabelInvalid));
+        assert_eq!(new_queries(&[question("", Type::PTR)], &[], true), Err(DnsSdError::LabelInvalid));
+    }
+
+    #[test]
+    fn test_query_from()
+    {
+        let queries = new_queries(&[question("_hap._tcp.local", Type::PTR), question("gw2.local", Type::AAAA)], &[known_answer(1)], true).unwrap();
+        let query = DnsSdQuery::from(&queries[0], queries[0].len()).unwrap();
+
+        assert_eq!(query.id, 0);
+        assert_eq!(query.questions, vec![question("_hap._tcp.local", Type::PTR), question("gw2.local", Type::AAAA)]);
+        assert!(query.unicast_response);
+        assert_eq!(query.known_answers.len(), 1);
+
+        let queries = new_queries(&[question("_hap._tcp.local", Type::PTR)], &[], false).unwrap();
+        assert!(!DnsSdQuery::from(&queries[0], queries[0].len()).unwrap().unicast_response);
+        assert_eq!(query.known_answers[0].label(), "_hap._tcp.local");
+        assert_eq!(query.known_answers[0].rdata_key(), b"accessory 1._hap._tcp.local");
+
+        let response = new_response(0, &[], &query.known_answers, &[]).unwrap();
+        assert_eq!(DnsSdQuery::from(&response, response.len()).err(), Some(DnsSdError::NotDnsSdQuery));
+    }
+
+    #[test]
+    fn test_new_response()
+    {
+        let answers = vec![
+            DnsSdResponse::PtrAnswer(known_answer(1)),
+            DnsSdResponse::SrvAnswer(SrvAnswer { label: String::from("Accessory 1._hap._tcp.local"), service: String::from("gw2.local"), priority: 1, weight: 2, port: 8000, class: 1, ttl: 120, cache_flush: true })
+        ];
+        let additional = vec![
+            DnsSdResponse::TxtAnswer(TxtAnswer { label: String::from("Accessory 1._hap._tcp.local"), records: Vec::new(), class: 1, ttl: 4500, cache_flush: true }),
+            DnsSdResponse::AAnswer(AAnswer { label: String::from("gw2.local"), address: Ipv4Addr::new(192, 168, 1, 2), class: 1, ttl: 120, cache_flush: true }),
+            DnsSdResponse::AaaaAnswer(AaaaAnswer { label: String::from("gw2.local"), address: Ipv6Addr::LOCALHOST, class: 1, ttl: 120, cache_flush: true })
+        ];
+
+        let response = new_response(0, &[], &answers, &additional).unwrap();
+        let header = DnsSdHeader::from(&response, response.len()).unwrap();
+        assert_eq!(header.flags, FLAGS_QR_RESPONSE | FLAGS_AA);
+        assert_eq!(header.answers_len, 2);
+        assert_eq!(header.additional_len, 3);
+
+        let parsed = DnsSdResponse::from(&response, response.len()).unwrap();
+        assert_eq!(parsed.len(), 5);
+        for (parsed, record) in parsed.iter().zip(answers.iter().chain(&additional))
+        {
+            assert_eq!(parsed.label(), record.label());
+            assert_eq!(parsed.rtype(), record.rtype());
+            assert_eq!(parsed.class(), record.class());
+            assert_eq!(parsed.ttl(), record.ttl());
+            assert_eq!(parsed.cache_flush(), record.cache_flush());
+        }
+        assert_eq!(parsed[1].rdata_key(), answers[1].rdata_key());
+
+        // TXT strings are sent as given, a TXT record without any has no data.
+        let DnsSdResponse::TxtAnswer(txt_answer) = &parsed[2] else { panic!("Expected TXT record") };
+        assert!(txt_answer.records.is_empty());
+
+        let legacy = new_response(0x1234, &[question("gw2.local", Type::A)], &[additional[1].clone().for_legacy_unicast(10)], &[]).unwrap();
+        let header = DnsSdHeader::from(&legacy, legacy.len()).unwrap();
+        assert_eq!(header.id, 0x1234);
+        assert_eq!(header.queries_len, 1);
+        assert_eq!(legacy[legacy.len() - 14..legacy.len() - 10], [0x00, 0x01, 0x00, 0x01]);
+        assert_eq!(legacy[legacy.len() - 10..legacy.len() - 6], 10_u32.to_be_bytes());
+    }
+
+    #[test]
+    fn test_message()
+    {
+        let srv_answer = DnsSdResponse::SrvAnswer(SrvAnswer { label: String::from("Accessory 1._hap._tcp.local"), service: String::from("gw2.local"), priority: 1, weight: 2, port: 8000, class: 1, ttl: 120, cache_flush: true });
+        let a_answer = DnsSdResponse::AAnswer(AAnswer { label: String::from("gw2.local"), address: Ipv4Addr::new(192, 168, 1, 2), class: 1, ttl: 120, cache_flush: true });
+        let txt_answer = DnsSdResponse::TxtAnswer(TxtAnswer { label: String::from("Accessory 1._hap._tcp.local"), records: vec![String::from("c#=2"), String::from("sf=1")], class: 1, ttl: 4500, cache_flush: true });
+        let records = vec![
+            DnsSdResponse::PtrAnswer(known_answer(1)),
+            srv_answer.clone(),
+            a_answer.clone(),
+            DnsSdResponse::AaaaAnswer(AaaaAnswer { label: String::from("gw2.local"), address: Ipv6Addr::LOCALHOST, class: 1, ttl: 120, cache_flush: true }),
+            DnsSdResponse::UnknownAnswer(UnknownAnswer { label: String::from("gw2.local"), rtype: Type::NSEC as u16, data: vec![0x03, 0x67, 0x77, 0x32, 0x05, 0x6c, 0x6f, 0x63, 0x61, 0x6c, 0x00, 0x00, 0x04, 0x40, 0x00, 0x00, 0x08], class: 1, ttl: 120, cache_flush: true })
+        ];
+
+        let mut response = Message::response(0);
+        response.add_answer(records[0].clone());
+        response.add_answer(records[1].clone());
+        response.add_authority(txt_answer.clone());
+        for record in &records[2..]
+        {
+            response.add_additional(record.clone());
+        }
+
+        let packet = response.to_bytes().unwrap();
+        let header = DnsSdHeader::from(&packet, packet.len()).unwrap();
+        assert_eq!((header.answers_len, header.authorities_len, header.additional_len), (2, 1, 3));
+
+        // The authority record is skipped.
+        let parsed = DnsSdResponse::from(&packet, packet.len()).unwrap();
+        assert_eq!(parsed.len(), records.len());
+        for (parsed, record) in parsed.iter().zip(&records)
+        {
+            assert!(parsed.is_same_record(record));
+            assert_eq!(parsed.label(), record.label());
+            assert_eq!(parsed.ttl(), record.ttl());
+            assert_eq!(parsed.cache_flush(), record.cache_flush());
+        }
+
+        // Every name after the first is compressed, down to the PTR and SRV targets.
+        let uncompressed: usize = records.iter().chain([&txt_answer]).map(|record| record.label().len() + 2 + 10 + record.rdata().unwrap().len()).sum();
+        assert!(packet.len() < DNS_HEADER_SIZE + uncompressed - 100);
+
+        // Records of queries are sent without the cache flush bit.
+        let mut query = Message::query();
+        query.add_question(Question { name: String::from("gw2.local"), qtype: Type::A }, false);
+        query.add_answer(a_answer);
+        query.set_truncated();
+        let packet = query.to_bytes().unwrap();
+        let parsed = DnsSdQuery::from(&packet, packet.len()).unwrap();
+        assert!(!parsed.unicast_response);
+        assert!(!parsed.known_answers[0].cache_flush());
+        assert_eq!(DnsSdHeader::from(&packet, packet.len()).unwrap().flags, FLAGS_TC);
+    }
+
+    #[test]
+    fn test_message_limits()
+    {
+        // A TXT record of 40 strings of 255 bytes is over the 9000 bytes of an mDNS message.
+        let txt = DnsSdResponse::TxtAnswer(TxtAnswer { label: String::from("Accessory 1._hap._tcp.local"), records: vec!["x".repeat(255); 40], class: 1, ttl: 4500, cache_flush: true });
+        let mut response = Message::response(0);
+        response.add_answer(txt);
+        assert_eq!(response.to_bytes(), Err(DnsSdError::PacketTooLarge));
+
+        let mut response = Message::response(0);
+        response.set_max_size(48);
+        response.add_answer(DnsSdResponse::PtrAnswer(known_answer(1)));
+        assert_eq!(response.to_bytes(), Err(DnsSdError::PacketTooLarge));
+
+        let name = |name: String|
+        {
+            let mut query = Message::query();
+            query.add_question(Question { name, qtype: Type::A }, false);
+            query.to_bytes()
+        };
+        assert!(name(format!("{}.local", "x".repeat(63))).is_ok());
+        assert_eq!(name(format!("{}.local", "x".repeat(64))), Err(DnsSdError::LabelToLong));
+        assert_eq!(name(vec!["x".repeat(63); 4].join(".")), Err(DnsSdError::LabelToLong));
+        assert_eq!(name(String::from("gw2..local")), Err(DnsSdError::LabelInvalid));
+    }
+
+    #[test]
+    fn test_new_query_with_known_answers()
+    {
+        let queries = new_queries(&[question("_hap._tcp.local", Type::PTR)], &[known_answer(1), known_answer(2)], true).unwrap();
+        assert_eq!(queries.len(), 1);
+
+        let header = DnsSdHeader::from(&queries[0], queries[0].len()).unwrap();
+        assert_eq!(header.flags, 0x0000);
+        assert_eq!(header.queries_len, 1);
+        assert_eq!(header.answers_len, 2);
+
+        // The first known answer follows the 33 bytes of header and question. Its name and the end
+        // of the instance name point to the question.
+        let answer = &queries[0][33..];
+        assert_eq!(answer[..2], [0xc0, 0x0c]);
+        assert_eq!(answer[2..6], [0x00, 0x0c, 0x00, 0x01]);
+        assert_eq!(answer[6..10], 4500_u32.to_be_bytes());
+        assert_eq!(answer[10..12], 14_u16.to_be_bytes());
+        assert_eq!(answer[12], 11);
+        assert_eq!(answer[13..24], *b"Accessory 1");
+        assert_eq!(answer[24..26], [0xc0, 0x0c]);
+    }
+
+    #[test]
+    fn test_new_query_known_answers_split()
+    {
+        let known_answers: Vec<PtrAnswer> = (0..100).map(known_answer).collect();
+        let queries = new_queries(&[question("_hap._tcp.local", Type::PTR)], &known_answers, true).unwrap();
+        assert!(queries.len() > 1);
+
+        let mut answers_len = 0;
+        for (i, query) in queries.iter().enumerate()
+        {
+            assert!(query.len() <= MAX_QUERY_PACKET_SIZE);
+
+            let header = DnsSdHeader::from(query, query.len()).unwrap();
+            let last = i == queries.len() - 1;
+            assert_eq!(header.flags & FLAGS_TC != 0, !last);
+            assert_eq!(header.queries_len, if i == 0 { 1 } else { 0 });
+            answers_len += header.answers_len;
+        }
+
+        assert_eq!(answers_len, 100);
+    }
+
     #[test]
     fn test_dns_response_from()
     {
@@ -623,7 +1599,7 @@ mod tests
                                   0x31, 0x05, 0x73, 0x23, 0x3d, 0x34, 0x37, 0x04, 0x73, 0x66, 0x3d, 0x30, 0x04, 0x63, 0x69, 0x3d,
                                   0x32, 0x0b, 0x73, 0x68, 0x3d, 0x6b, 0x37, 0x50, 0x76, 0x43, 0x67, 0x3d, 0x3d ];
 
-        let (label, responses) = DnsSdResponse::from(&packet, 221).unwrap();
+        let responses = DnsSdResponse::from(&packet, 221).unwrap();
 
         assert_eq!(responses.len(), 3);
         let mut matches = 0;
@@ -636,6 +1612,9 @@ mod tests
                     assert_eq!(answer.label, "DIRIGERA._hap._tcp.local");
                     assert_eq!(answer.service, "gw2-8ff6ed210a48.local");
                     assert_eq!(answer.port, 8000);
+                    assert_eq!(answer.priority, 0);
+                    assert_eq!(answer.weight, 0);
+                    assert_eq!(answer.ttl, 10);
                     matches += 1;
                 },
                 DnsSdResponse::TxtAnswer(answer) =>
@@ -664,4 +1643,151 @@ mod tests
         }
         assert_eq!(matches, 3);
     }
+
+    #[test]
+    fn test_dns_response_from_no_questions()
+    {
+        let packet: [u8; 229] =
+        [
+            0x00, 0x00, 0x84, 0x00, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x04, 0x5f, 0x68, 0x61,
+            0x70, 0x04, 0x5f, 0x74, 0x63, 0x70, 0x05, 0x6c, 0x6f, 0x63, 0x61, 0x6c, 0x00, 0x00, 0x0c, 0x00,
+            0x01, 0x00, 0x00, 0x11, 0x94, 0x00, 0x0b, 0x08, 0x44, 0x49, 0x52, 0x49, 0x47, 0x45, 0x52, 0x41,
+            0xc0, 0x0c, 0xc0, 0x27, 0x00, 0x10, 0x80, 0x01, 0x00, 0x00, 0x11, 0x94, 0x00, 0x66, 0x05, 0x63,
+            0x23, 0x3d, 0x32, 0x32, 0x04, 0x66, 0x66, 0x3d, 0x31, 0x14, 0x69, 0x64, 0x3d, 0x42, 0x35, 0x3a,
+            0x42, 0x30, 0x3a, 0x41, 0x30, 0x3a, 0x36, 0x37, 0x3a, 0x42, 0x34, 0x3a, 0x36, 0x39, 0x22, 0x6d,
+            0x64, 0x3d, 0x44, 0x49, 0x52, 0x49, 0x47, 0x45, 0x52, 0x41, 0x20, 0x48, 0x75, 0x62, 0x20, 0x66,
+            0x6f, 0x72, 0x20, 0x73, 0x6d, 0x61, 0x72, 0x74, 0x20, 0x70, 0x72, 0x6f, 0x64, 0x75, 0x63, 0x74,
+            0x73, 0x06, 0x70, 0x76, 0x3d, 0x31, 0x2e, 0x31, 0x05, 0x73, 0x23, 0x3d, 0x32, 0x30, 0x04, 0x73,
+            0x66, 0x3d, 0x30, 0x04, 0x63, 0x69, 0x3d, 0x32, 0x0b, 0x73, 0x68, 0x3d, 0x6b, 0x37, 0x50, 0x76,
+            0x43, 0x67, 0x3d, 0x3d, 0xc0, 0x27, 0x00, 0x21, 0x80, 0x01, 0x00, 0x00, 0x00, 0x78, 0x00, 0x19,
+            0x00, 0x00, 0x00, 0x00, 0x1f, 0x40, 0x10, 0x67, 0x77, 0x32, 0x2d, 0x38, 0x66, 0x66, 0x36, 0x65,
+            0x64, 0x32, 0x31, 0x30, 0x61, 0x34, 0x38, 0xc0, 0x16, 0xc0, 0xb6, 0x00, 0x1c, 0x80, 0x01, 0x00,
+            0x00, 0x00, 0x78, 0x00, 0x10, 0xfd, 0x05, 0x0b, 0x30, 0x32, 0x24, 0x4a, 0x5c, 0x6a, 0xec, 0x8a,
+            0xff, 0xfe, 0x00, 0xd0, 0xed
+        ];
+
+        let responses = DnsSdResponse::from(&packet, 229).unwrap();
+
+        assert_eq!(responses.len(), 4);
+        match &responses[0]
+        {
+            DnsSdResponse::PtrAnswer(answer) =>
+            {
+                assert_eq!(answer.label, "_hap._tcp.local");
+                assert_eq!(answer.service, "DIRIGERA._hap._tcp.local");
+            },
+            _ => panic!("Expected PTR answer")
+        }
+        assert_eq!(responses[0].ttl(), 4500);
+        assert!(!responses[0].cache_flush());
+        assert!(responses[1].cache_flush());
+        assert_eq!(responses[1].class(), 1);
+        assert_eq!(responses[1].label(), "DIRIGERA._hap._tcp.local");
+        assert_eq!(responses[1].ttl(), 4500);
+        assert_eq!(responses[2].label(), "DIRIGERA._hap._tcp.local");
+        assert_eq!(responses[2].ttl(), 120);
+        assert_eq!(responses[3].label(), "gw2-8ff6ed210a48.local");
+        assert_eq!(responses[3].ttl(), 120);
+    }
+
+    #[test]
+    fn test_dns_response_from_unknown_type()
+    {
+        let packet: [u8; 57] =
+        [
+            0x00, 0x00, 0x84, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x03, 0x67, 0x77, 0x32,
+            0x05, 0x6c, 0x6f, 0x63, 0x61, 0x6c, 0x00, 0x00, 0x0d, 0x80, 0x01, 0x00, 0x00, 0x00, 0x78, 0x00,
+            0x08, 0x03, 0x41, 0x52, 0x4d, 0x03, 0x4c, 0x4e, 0x58, 0xc0, 0x0c, 0x00, 0x01, 0x80, 0x01, 0x00,
+            0x00, 0x00, 0x78, 0x00, 0x04, 0xc0, 0xa8, 0x01, 0x02
+        ];
+
+        let responses = DnsSdResponse::from(&packet, 57).unwrap();
+
+        assert_eq!(responses.len(), 2);
+        match &responses[0]
+        {
+            DnsSdResponse::UnknownAnswer(answer) =>
+            {
+                assert_eq!(answer.label, "gw2.local");
+                assert_eq!(answer.rtype, 13);
+                assert_eq!(answer.data, [0x03, 0x41, 0x52, 0x4d, 0x03, 0x4c, 0x4e, 0x58]);
+                assert_eq!(answer.ttl, 120);
+            },
+            _ => panic!("Expected unknown answer")
+        }
+        match &responses[1]
+        {
+            DnsSdResponse::AAnswer(answer) =>
+            {
+                assert_eq!(answer.label, "gw2.local");
+                assert_eq!(answer.address, Ipv4Addr::new(192, 168, 1, 2));
+            },
+            _ => panic!("Expected A answer")
+        }
+    }
+
+    #[test]
+    fn test_dns_response_from_truncated()
+    {
+        let packet: [u8; 57] =
+        [
+            0x00, 0x00, 0x84, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x03, 0x67, 0x77, 0x32,
+            0x05, 0x6c, 0x6f, 0x63, 0x61, 0x6c, 0x00, 0x00, 0x0d, 0x80, 0x01, 0x00, 0x00, 0x00, 0x78, 0x00,
+            0x08, 0x03, 0x41, 0x52, 0x4d, 0x03, 0x4c, 0x4e, 0x58, 0xc0, 0x0c, 0x00, 0x01, 0x80, 0x01, 0x00,
+            0x00, 0x00, 0x78, 0x00, 0x04, 0xc0, 0xa8, 0x01, 0x02
+        ];
+
+        for count in 0..packet.len()
+        {
+            assert!(DnsSdResponse::from(&packet, count).is_err());
+            assert!(DnsSdResponse::from(&packet[..count], count).is_err());
+        }
+
+        assert!(matches!(DnsSdResponse::from(&packet, 55), Err(DnsSdError::Truncated)));
+        assert!(matches!(DnsSdResponse::from(&packet, 58), Err(DnsSdError::Truncated)));
+        assert!(DnsSdResponse::from(&packet, 57).is_ok());
+    }
+
+    #[test]
+    fn test_dns_response_from_bad_lengths()
+    {
+        // A record claiming more data than the packet holds.
+        let mut packet: [u8; 57] =
+        [
+            0x00, 0x00, 0x84, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x03, 0x67, 0x77, 0x32,
+            0x05, 0x6c, 0x6f, 0x63, 0x61, 0x6c, 0x00, 0x00, 0x0d, 0x80, 0x01, 0x00, 0x00, 0x00, 0x78, 0xff,
+            0xff, 0x03, 0x41, 0x52, 0x4d, 0x03, 0x4c, 0x4e, 0x58, 0xc0, 0x0c, 0x00, 0x01, 0x80, 0x01, 0x00,
+            0x00, 0x00, 0x78, 0x00, 0x04, 0xc0, 0xa8, 0x01, 0x02
+        ];
+
+        assert!(matches!(DnsSdResponse::from(&packet, 57), Err(DnsSdError::Truncated)));
+
+        // A name with a label running past the end of the packet.
+        packet[31] = 0x00;
+        packet[32] = 0x08;
+        packet[41] = 0x3f;
+        assert!(matches!(DnsSdResponse::from(&packet, 57), Err(DnsSdError::Truncated)));
+
+        // A name pointing at itself.
+        packet[41] = 0xc0;
+        packet[42] = 0x29;
+        assert!(matches!(DnsSdResponse::from(&packet, 57), Err(DnsSdError::LabelPtrForward)));
+    }
+
+    #[test]
+    fn test_label_to_string_truncated()
+    {
+        let packet: [u8; 17] =
+        [
+            0x04, 0x5f, 0x68, 0x61, 0x70, 0x04, 0x5f, 0x74, 0x63, 0x70, 0x05, 0x6c, 0x6f, 0x63,
+            0x61, 0x6c, 0x00,
+        ];
+
+        for count in 0..packet.len()
+        {
+            assert!(matches!(DnsSdResponse::label_to_string(&packet[..count], 0), Err(DnsSdError::Truncated)));
+        }
+
+        assert!(matches!(DnsSdResponse::label_to_string(&[0xc0], 0), Err(DnsSdError::Truncated)));
+    }
 }
@@ -1,12 +1,13 @@
 use std::{ error::Error, fmt::Display };
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum DnsSdError
 {
     Timeout,
     UdpSocketError,
     InvalidDnsSdHeader,
     NotDnsSdResponse,
+    NotDnsSdQuery,
     NoAnswers,
     InvalidMdnsType,
     LabelToLong,
@@ -14,7 +15,12 @@ pub enum DnsSdError
     LabelCompressionLoop,
     LabelPtrForward,
     LabelInvalid,
-    InvalidDnsSdResponse
+    InvalidDnsSdResponse,
+    Truncated,
+    PacketTooLarge,
+    InvalidRecord,
+    ThreadPanicked,
+    InterfaceNotFound
 }
 
 impl Error for DnsSdError
@@ -69,6 +75,10 @@ impl Display for DnsSdError
             {
                 write!(f, "DNS-SD error: Not mDNS response")
             },
+            DnsSdError::NotDnsSdQuery =>
+            {
+                write!(f, "DNS-SD error: Not mDNS query")
+            },
             DnsSdError::NoAnswers =>
             {
                 write!(f, "DNS-SD error: No answers")
@@ -100,6 +110,26 @@ impl Display for DnsSdError
             DnsSdError::InvalidDnsSdResponse =>
             {
                 write!(f, "DNS-SD error: Invalid mDNS response")
+            },
+            DnsSdError::Truncated =>
+            {
+                write!(f, "DNS-SD error: Packet truncated")
+            },
+            DnsSdError::PacketTooLarge =>
+            {
+                write!(f, "DNS-SD error: Packet too large")
+            },
+            DnsSdError::InvalidRecord =>
+            {
+                write!(f, "DNS-SD error: Invalid record")
+            },
+            DnsSdError::ThreadPanicked =>
+            {
+                write!(f, "DNS-SD error: Discovery thread panicked")
+            },
+            DnsSdError::InterfaceNotFound =>
+            {
+                write!(f, "DNS-SD error: Network interface not found")
             }
         }
     }
@@ -0,0 +1,19 @@
+// Entry points for the fuzz targets in fuzz/. Not part of the public API.
+
+use crate::dns::{ DnsSdHeader, DnsSdResponse };
+
+pub fn parse_response(buffer: &[u8])
+{
+    let _ = DnsSdResponse::from(buffer, buffer.len());
+}
+
+pub fn parse_label(buffer: &[u8], offset: usize)
+{
+    let _ = DnsSdResponse::label_to_string(buffer, offset);
+}
+
+// Returns the parsed header serialized again, so the target can check it round-trips.
+pub fn parse_header(buffer: &[u8]) -> Option<Vec<u8>>
+{
+    DnsSdHeader::from(buffer, buffer.len()).ok().map(|header| header.to_bytes())
+}
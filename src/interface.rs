@@ -0,0 +1,208 @@
+use std::net::{ Ipv4Addr, Ipv6Addr };
+use log::debug;
+
+use crate::dnssd_error::DnsSdError;
+
+// A network interface discovery can run on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NetworkInterface
+{
+    pub index: u32,
+    // E.g. "eth0" or "en0".
+    pub name: String,
+    pub ipv4: Vec<Ipv4Addr>,
+    pub ipv6: Vec<Ipv6Addr>
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum InterfaceSelection
+{
+    Name(String),
+    Index(u32)
+}
+
+// How the interfaces to run on are picked, kept so they can be looked up again after a change.
+#[derive(Clone, Debug)]
+pub enum InterfaceFilter
+{
+    Default,
+    Selected(Vec<InterfaceSelection>)
+}
+
+impl InterfaceFilter
+{
+    // Interfaces currently matching the filter, empty if none of them is up.
+    pub fn current(&self) -> Vec<NetworkInterface>
+    {
+        match self
+        {
+            InterfaceFilter::Default => default_interface().into_iter().collect(),
+            InterfaceFilter::Selected(selections) =>
+            {
+                list_interfaces()
+                    .into_iter()
+                    .filter(|interface| selections.iter().any(|selection| interface.matches(selection)))
+                    .collect()
+            }
+        }
+    }
+}
+
+impl NetworkInterface
+{
+    fn from(interface: default_net::Interface) -> NetworkInterface
+    {
+        NetworkInterface
+        {
+            index: interface.index,
+            name: interface.name,
+            ipv4: interface.ipv4.iter().map(|net| net.addr).collect(),
+            ipv6: interface.ipv6.iter().map(|net| net.addr).collect()
+        }
+    }
+
+    fn matches(&self, selection: &InterfaceSelection) -> bool
+    {
+        match selection
+        {
+            InterfaceSelection::Name(name) => self.name == *name,
+            InterfaceSelection::Index(index) => self.index == *index
+        }
+    }
+
+    fn same_addresses(&self, other: &NetworkInterface) -> bool
+    {
+        let mut ipv4 = (self.ipv4.clone(), other.ipv4.clone());
+        let mut ipv6 = (self.ipv6.clone(), other.ipv6.clone());
+        ipv4.0.sort();
+        ipv4.1.sort();
+        ipv6.0.sort();
+        ipv6.1.sort();
+
+        ipv4.0 == ipv4.1 && ipv6.0 == ipv6.1
+    }
+}
+
+// Interfaces that are up, not loopback and support multicast.
+pub fn list_interfaces() -> Vec<NetworkInterface>
+{
+    default_net::get_interfaces()
+        .into_iter()
+        .filter(|interface| interface.is_up() && !interface.is_loopback() && interface.is_multicast())
+        .map(NetworkInterface::from)
+        .collect()
+}
+
+// Interface of the default route.
+pub fn default_interface() -> Result<NetworkInterface, DnsSdError>
+{
+    match default_net::get_default_interface()
+    {
+        Ok(interface) => Ok(NetworkInterface::from(interface)),
+        Err(err) =>
+        {
+            debug!("Failed to get default interface: {}", err);
+            Err(DnsSdError::InterfaceNotFound)
+        }
+    }
+}
+
+pub fn select_interfaces(selections: &[InterfaceSelection]) -> Result<Vec<NetworkInterface>, DnsSdError>
+{
+    select(list_interfaces(), selections)
+}
+
+// Every selection must match an interface, an interface selected twice is only used once.
+fn select(interfaces: Vec<NetworkInterface>, selections: &[InterfaceSelection]) -> Result<Vec<NetworkInterface>, DnsSdError>
+{
+    if selections.is_empty()
+    {
+        return Err(DnsSdError::InterfaceNotFound);
+    }
+
+    for selection in selections
+    {
+        if !interfaces.iter().any(|interface| interface.matches(selection))
+        {
+            debug!("No interface matches {:?}", selection);
+            return Err(DnsSdError::InterfaceNotFound);
+        }
+    }
+
+    Ok(interfaces.into_iter()
+        .filter(|interface| selections.iter().any(|selection| interface.matches(selection)))
+        .collect())
+}
+
+// Interfaces that went away, interfaces that are new, and interfaces that are still there with
+// other addresses, as they are now. An interface is the same one as long as its index is.
+pub fn diff_interfaces(active: &[NetworkInterface], current: &[NetworkInterface]) -> (Vec<NetworkInterface>, Vec<NetworkInterface>, Vec<NetworkInterface>)
+{
+    let gone = active.iter()
+        .filter(|interface| !current.iter().any(|other| other.index == interface.index))
+        .cloned()
+        .collect();
+    let added = current.iter()
+        .filter(|interface| !active.iter().any(|other| other.index == interface.index))
+        .cloned()
+        .collect();
+    let readdressed = current.iter()
+        .filter(|interface| active.iter().any(|other| other.index == interface.index && !other.same_addresses(interface)))
+        .cloned()
+        .collect();
+
+    (gone, added, readdressed)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn interface(index: u32, name: &str) -> NetworkInterface
+    {
+        NetworkInterface
+        {
+            index,
+            name: String::from(name),
+            ipv4: vec![Ipv4Addr::new(192, 168, index as u8, 1)],
+            ipv6: Vec::new()
+        }
+    }
+
+    #[test]
+    fn test_select()
+    {
+        let interfaces = vec![interface(2, "eth0"), interface(3, "eth1"), interface(4, "eth1.10")];
+
+        let selected = select(interfaces.clone(), &[InterfaceSelection::Name(String::from("eth1.10")), InterfaceSelection::Index(2), InterfaceSelection::Name(String::from("eth0"))]).unwrap();
+        assert_eq!(selected, vec![interface(2, "eth0"), interface(4, "eth1.10")]);
+
+        assert_eq!(select(interfaces.clone(), &[InterfaceSelection::Index(2), InterfaceSelection::Index(5)]), Err(DnsSdError::InterfaceNotFound));
+        assert_eq!(select(interfaces, &[]), Err(DnsSdError::InterfaceNotFound));
+    }
+
+    #[test]
+    fn test_diff_interfaces()
+    {
+        let mut readdressed = interface(3, "wlan0");
+        readdressed.ipv4 = vec![Ipv4Addr::new(10, 0, 0, 7)];
+
+        let active = vec![interface(2, "eth0"), interface(3, "wlan0")];
+        let current = vec![readdressed.clone(), interface(4, "veth0")];
+
+        let (gone, added, changed) = diff_interfaces(&active, &current);
+        assert_eq!(gone, vec![interface(2, "eth0")]);
+        assert_eq!(added, vec![interface(4, "veth0")]);
+        assert_eq!(changed, vec![readdressed]);
+
+        assert_eq!(diff_interfaces(&active, &active), (Vec::new(), Vec::new(), Vec::new()));
+
+        // The same addresses in another order are no change.
+        let mut reordered = interface(3, "wlan0");
+        reordered.ipv4.push(Ipv4Addr::new(10, 0, 0, 7));
+        let mut active = reordered.clone();
+        active.ipv4.reverse();
+        assert_eq!(diff_interfaces(&[active], &[reordered]), (Vec::new(), Vec::new(), Vec::new()));
+    }
+}
@@ -1,129 +1,156 @@
-use std::{sync::{ Arc, Mutex }, net::IpAddr };
+use std::{sync::{ Arc, Mutex, mpsc }, net::IpAddr, time::{ Duration, Instant } };
 
 mod dnssd_error;
 mod dns;
 mod socket;
+mod record_cache;
 mod discovery_handler;
 mod sender;
+mod service_instance;
+mod service_event;
+mod interface;
+mod scheduler;
+mod service_registration;
+mod registry;
+mod responder;
+mod record;
+
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzzing;
 
-use dnssd_error::DnsSdError;
 use discovery_handler::DiscoveryHandler;
 use sender::Sender;
 use dns::DnsSdResponse;
+use service_event::EventDispatcher;
+use interface::InterfaceFilter;
+
+pub use dnssd_error::DnsSdError;
+pub use service_instance::ServiceInstance;
+pub use service_event::ServiceEvent;
+pub use interface::{ NetworkInterface, InterfaceSelection, list_interfaces };
+pub use scheduler::QuerySchedule;
+pub use service_registration::{ ServiceRegistration, RegistrationEvent };
+pub use responder::ServiceResponder;
+pub use record::{ Record, RData };
 
 #[derive(Clone)]
 pub enum IpType
 {
     V4,
-    V6
+    V6,
+    // Query and listen over both IPv4 and IPv6, A and AAAA records end up in the same cache.
+    DualStack
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DiscoveryMode
+{
+    // Send queries for the services looked for and listen for the responses.
+    Active,
+    // Only listen to the mDNS traffic on port 5353 and cache all of it.
+    Passive,
+    ActiveAndPassive
+}
+
+// Order of addresses returned for a host in dual-stack mode.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AddressPreference
+{
+    Ipv4First,
+    Ipv6First
 }
 
 pub struct ServiceDiscovery
 {
     discovery_handler: Arc<Mutex<DiscoveryHandler>>,
-    ip_type: IpType,
-    _sender: Sender
+    event_dispatcher: Arc<Mutex<EventDispatcher>>,
+    sender: Sender
 }
 
 impl ServiceDiscovery
 {
+    // Discover services on the interface of the default route.
     pub fn new(ip_type: IpType) -> Result<ServiceDiscovery, DnsSdError>
     {
-        let discovery_handler: DiscoveryHandler = DiscoveryHandler::new();
+        let interface = interface::default_interface()?;
+        ServiceDiscovery::on_interfaces(ip_type, InterfaceFilter::Default, vec![interface])
+    }
+
+    // Discover services on the selected interfaces, with one socket each. Fails if a selection does
+    // not match any of the interfaces from list_interfaces.
+    pub fn with_interfaces(ip_type: IpType, selections: &[InterfaceSelection]) -> Result<ServiceDiscovery, DnsSdError>
+    {
+        let interfaces = interface::select_interfaces(selections)?;
+        ServiceDiscovery::on_interfaces(ip_type, InterfaceFilter::Selected(selections.to_vec()), interfaces)
+    }
+
+    // Interfaces are checked for changes every few seconds afterwards. Sockets are rebuilt for
+    // interfaces that come up or change address, and records heard on interfaces that went away
+    // are dropped.
+    fn on_interfaces(ip_type: IpType, filter: InterfaceFilter, interfaces: Vec<NetworkInterface>) -> Result<ServiceDiscovery, DnsSdError>
+    {
+        let discovery_handler: DiscoveryHandler = DiscoveryHandler::new(ip_type.clone());
         let handler = Arc::new(Mutex::new(discovery_handler));
-        let sender = Sender::new(handler.clone(), &ip_type)?;
+        let dispatcher = Arc::new(Mutex::new(EventDispatcher::new()));
+        let sender = Sender::new(handler.clone(), dispatcher.clone(), &ip_type, filter, interfaces)?;
 
         Ok(ServiceDiscovery
         {
             discovery_handler: handler,
-            ip_type: ip_type,
-            _sender: sender
+            event_dispatcher: dispatcher,
+            sender
         })
     }
 
     pub fn find_service(&mut self, service: &str)
     {
         self.discovery_handler.lock().unwrap().add_service(String::from(service));
+        self.sender.schedule_queries();
+    }
+
+    // Change when queries are sent. Querying starts over with the new schedule.
+    pub fn set_query_schedule(&mut self, schedule: QuerySchedule)
+    {
+        self.sender.set_query_schedule(schedule);
     }
 
     pub fn get_ip_address(&self, service: &str) -> Option<IpAddr>
     {
-        let handler = self.discovery_handler.lock().unwrap();
-        let maybe_services = handler.get_found_services(service);
-        if maybe_services.is_none()
-        {
-            return None;
-        }
+        let mut handler = self.discovery_handler.lock().unwrap();
+        handler.remove_expired();
 
-        let timed_services = maybe_services.unwrap();
-        if let Some(timed_service) = timed_services.last()
+        for cached in handler.get_instance_records(service)
         {
-            for service in &timed_service.responses
+            if let DnsSdResponse::SrvAnswer(srv_answer) = &cached.response
             {
-                match service
+                if let Some(address) = handler.get_addresses(&srv_answer.service).first()
                 {
-                    DnsSdResponse::AAnswer(a_answer) =>
-                    {
-                        match self.ip_type
-                        {
-                            IpType::V6 => continue,
-                            _ => ()
-                        }
-
-                        return Some(IpAddr::V4(a_answer.address));
-                    },
-                    DnsSdResponse::AaaaAnswer(aaaa_answer) =>
-                    {
-                        match self.ip_type
-                        {
-                            IpType::V4 => continue,
-                            _ => ()
-                        }
-
-                        return Some(IpAddr::V6(aaaa_answer.address));
-                    }
-                    _ =>
-                    {
-                        continue
-                    }
+                    return Some(*address);
                 }
             }
         }
 
-        return None;
+        None
     }
 
     pub fn get_port(&self, service: &str) -> Option<u16>
     {
-        let handler = self.discovery_handler.lock().unwrap();
-        let maybe_services = handler.get_found_services(service);
-        if maybe_services.is_none()
-        {
-            return None;
-        }
+        let mut handler = self.discovery_handler.lock().unwrap();
+        handler.remove_expired();
 
-        let timed_services = maybe_services.unwrap();
-        if let Some(timed_service) = timed_services.last()
+        for cached in handler.get_instance_records(service)
         {
-            for service in &timed_service.responses
+            if let DnsSdResponse::SrvAnswer(srv_answer) = &cached.response
             {
-                match service
-                {
-                    DnsSdResponse::SrvAnswer(srv_answer) =>
-                    {
-                        return Some(srv_answer.port);
-                    },
-                    _ =>
-                    {
-                        continue
-                    }
-                }
+                return Some(srv_answer.port);
             }
         }
 
-        return None;
+        None
     }
 
+    #[allow(clippy::needless_return, clippy::question_mark)]
     pub fn get_ip_and_port(&self, service: &str) -> Option<(IpAddr, u16)>
     {
         let ip = self.get_ip_address(service);
@@ -143,37 +170,191 @@ impl ServiceDiscovery
 
     pub fn get_txt_records(&self, service: &str) -> Option<Vec<String>>
     {
-        let handler = self.discovery_handler.lock().unwrap();
-        let maybe_services = handler.get_found_services(service);
-        if maybe_services.is_none()
+        let mut handler = self.discovery_handler.lock().unwrap();
+        handler.remove_expired();
+
+        for cached in handler.get_instance_records(service)
+        {
+            if let DnsSdResponse::TxtAnswer(txt_answer) = &cached.response
+            {
+                return Some(txt_answer.records.clone());
+            }
+        }
+
+        None
+    }
+
+    // Switch between sending queries, listening to all mDNS traffic or both. Discovery starts
+    // active. Listening fails if port 5353 can not be shared with other mDNS software on the host.
+    pub fn set_mode(&mut self, mode: DiscoveryMode) -> Result<(), DnsSdError>
+    {
+        self.sender.set_mode(mode)?;
+        self.discovery_handler.lock().unwrap().set_cache_all(mode != DiscoveryMode::Active);
+
+        Ok(())
+    }
+
+    // Which IP version comes first when a host has both A and AAAA records. Defaults to IPv4, as
+    // IPv6 addresses from mDNS are often link-local and need a scope to be used.
+    pub fn set_address_preference(&mut self, preference: AddressPreference)
+    {
+        self.discovery_handler.lock().unwrap().set_address_preference(preference);
+    }
+
+    // Limit the memory used by the record cache, in bytes. When full, records closest to expiring
+    // are evicted first. Records larger than the limit are not cached.
+    pub fn set_max_cache_size(&mut self, max_bytes: usize)
+    {
+        self.discovery_handler.lock().unwrap().set_max_cache_size(max_bytes);
+    }
+
+    // Names of all discovered instances of a service, e.g. "DIRIGERA._hap._tcp.local".
+    pub fn get_instances(&self, service: &str) -> Vec<String>
+    {
+        let mut handler = self.discovery_handler.lock().unwrap();
+        handler.remove_expired();
+        handler.get_instances(service)
+    }
+
+    // Single fields of get_service_instance, taken from the same read of the cache.
+    pub fn get_instance_host(&self, instance: &str) -> Option<String>
+    {
+        self.get_service_instance(instance).map(|instance| instance.host)
+    }
+
+    pub fn get_instance_port(&self, instance: &str) -> Option<u16>
+    {
+        self.get_service_instance(instance).map(|instance| instance.port)
+    }
+
+    pub fn get_instance_txt_records(&self, instance: &str) -> Option<Vec<String>>
+    {
+        self.get_service_instance(instance).map(|instance| instance.txt_records)
+    }
+
+    pub fn get_instance_ip_addresses(&self, instance: &str) -> Vec<IpAddr>
+    {
+        self.get_service_instance(instance).map(|instance| instance.addresses).unwrap_or_default()
+    }
+
+    // All resolved instances of a service, read from the cache in one go.
+    pub fn get_service_instances(&self, service: &str) -> Vec<ServiceInstance>
+    {
+        let mut handler = self.discovery_handler.lock().unwrap();
+        handler.remove_expired();
+
+        handler.get_service_instances(service)
+    }
+
+    // A single resolved instance by its full name, e.g. "DIRIGERA._hap._tcp.local".
+    pub fn get_service_instance(&self, instance: &str) -> Option<ServiceInstance>
+    {
+        let mut handler = self.discovery_handler.lock().unwrap();
+        handler.remove_expired();
+
+        let service = handler.get_service_of(instance)?.clone();
+        if handler.is_removed(instance)
         {
             return None;
         }
 
-        let timed_services = maybe_services.unwrap();
-        if let Some(time_service) = timed_services.last()
+        handler.get_service_instance(&service, instance)
+    }
+
+    // Wait until an instance of the service has an SRV record and at least one address. The service
+    // is added to the ones being looked for if needed and stays there after the call returns.
+    pub fn resolve(&self, service: &str, timeout: Duration) -> Result<ServiceInstance, DnsSdError>
+    {
+        let deadline = Instant::now() + timeout;
+
+        // Subscribe before looking in the cache so an instance resolved in between is not missed.
+        let events = self.subscribe();
+
         {
-            for service in &time_service.responses
+            let mut handler = self.discovery_handler.lock().unwrap();
+            if !handler.get_services().iter().any(|found| found == service)
             {
-                match service
+                handler.add_service(String::from(service));
+            }
+        }
+        self.sender.schedule_queries();
+
+        let resolved = self.get_service_instances(service)
+            .into_iter()
+            .find(|instance| !instance.addresses.is_empty());
+        if let Some(instance) = resolved
+        {
+            return Ok(instance);
+        }
+
+        loop
+        {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match events.recv_timeout(remaining)
+            {
+                Ok(ServiceEvent::Resolved(instance)) | Ok(ServiceEvent::Updated(instance)) =>
                 {
-                    DnsSdResponse::TxtAnswer(txt_answer) =>
-                    {
-                        return Some(txt_answer.records.clone());
-                    },
-                    _ =>
+                    if instance.service().eq_ignore_ascii_case(service)
                     {
-                        continue
+                        return Ok(instance);
                     }
-                }
+                },
+                Ok(_) => continue,
+                Err(_) => return Err(DnsSdError::Timeout)
             }
         }
+    }
+
+    // Receive events from the listen thread as instances are found, resolved, updated and removed.
+    // Only events after the call are delivered.
+    pub fn subscribe(&self) -> mpsc::Receiver<ServiceEvent>
+    {
+        self.event_dispatcher.lock().unwrap().subscribe()
+    }
+
+    // Call a function from the listen thread for every event. The callback must not subscribe or
+    // add callbacks itself.
+    pub fn add_event_callback<F>(&self, callback: F)
+        where F: FnMut(&ServiceEvent) + Send + 'static
+    {
+        self.event_dispatcher.lock().unwrap().add_callback(Box::new(callback));
+    }
 
-        return None;
+    // Whether the instance said goodbye. That is only known until its records are evicted, about a
+    // second later.
+    pub fn is_removed(&self, instance: &str) -> bool
+    {
+        self.discovery_handler.lock().unwrap().is_removed(instance)
     }
 
     pub fn stop_find_service(&mut self, service: &str)
     {
         self.discovery_handler.lock().unwrap().remove_service(String::from(service));
     }
+
+    // Stop the send and listen threads and report any error they ended with. Dropping the
+    // ServiceDiscovery stops them as well, but errors are then only logged.
+    pub fn shutdown(&mut self) -> Result<(), DnsSdError>
+    {
+        self.sender.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn test_resolve_timeout()
+    {
+        let service_discovery = ServiceDiscovery::new(IpType::V4).unwrap();
+
+        let start = Instant::now();
+        let result = service_discovery.resolve("_libdnssd-test._udp.local", Duration::from_millis(300));
+
+        assert_eq!(result, Err(DnsSdError::Timeout));
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        assert!(service_discovery.discovery_handler.lock().unwrap().get_services().contains(&String::from("_libdnssd-test._udp.local")));
+    }
 }
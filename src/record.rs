@@ -0,0 +1,238 @@
+use std::net::{ Ipv4Addr, Ipv6Addr };
+
+use crate::dns::{ uncompressed_rdata, AAnswer, AaaaAnswer, DnsSdResponse, Message, PtrAnswer, SrvAnswer, TxtAnswer, Type, UnknownAnswer };
+use crate::dnssd_error::DnsSdError;
+
+const CLASS_CACHE_FLUSH_MASK: u16 = 0x8000;
+
+// A resource record as it is sent, with everything needed to write it back exactly.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record
+{
+    pub name: String,
+    pub rtype: u16,
+    // Class without the cache flush bit, 1 for IN.
+    pub class: u16,
+    pub cache_flush: bool,
+    pub ttl: u32,
+    pub rdata: RData
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RData
+{
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Ptr(String),
+    Srv { priority: u16, weight: u16, port: u16, target: String },
+    Txt(Vec<String>),
+    // Data of types other than the above, as received but with the names of NS, CNAME, SOA, MX and
+    // NSEC records uncompressed.
+    Unknown(Vec<u8>)
+}
+
+impl Record
+{
+    // All records in the answer and additional sections of an mDNS response.
+    pub fn parse(buffer: &[u8]) -> Result<Vec<Record>, DnsSdError>
+    {
+        Ok(DnsSdResponse::from(buffer, buffer.len())?.into_iter().map(Record::from).collect())
+    }
+
+    // An mDNS response with the records as answers, names compressed.
+    pub fn encode(records: &[Record]) -> Result<Vec<u8>, DnsSdError>
+    {
+        let mut response = Message::response(0);
+        for record in records
+        {
+            response.add_answer(record.to_response()?);
+        }
+
+        response.to_bytes()
+    }
+
+    // The type must be the one of the data, so the record is read back the same way. Unknown data
+    // can't use a type that is parsed, and names in it can't be compressed.
+    pub(crate) fn to_response(&self) -> Result<DnsSdResponse, DnsSdError>
+    {
+        if self.class & CLASS_CACHE_FLUSH_MASK != 0
+        {
+            return Err(DnsSdError::InvalidRecord);
+        }
+
+        let label = self.name.clone();
+        let (class, ttl, cache_flush) = (self.class, self.ttl, self.cache_flush);
+
+        let (rtype, response) = match &self.rdata
+        {
+            RData::A(address) => (Type::A as u16, DnsSdResponse::AAnswer(AAnswer { label, address: *address, class, ttl, cache_flush })),
+            RData::Aaaa(address) => (Type::AAAA as u16, DnsSdResponse::AaaaAnswer(AaaaAnswer { label, address: *address, class, ttl, cache_flush })),
+            RData::Ptr(target) => (Type::PTR as u16, DnsSdResponse::PtrAnswer(PtrAnswer { label, service: target.clone(), class, ttl, cache_flush })),
+            RData::Srv { priority, weight, port, target } =>
+            {
+                (Type::SRV as u16, DnsSdResponse::SrvAnswer(SrvAnswer { label, service: target.clone(), priority: *priority, weight: *weight, port: *port, class, ttl, cache_flush }))
+            },
+            RData::Txt(records) => (Type::TXT as u16, DnsSdResponse::TxtAnswer(TxtAnswer { label, records: records.clone(), class, ttl, cache_flush })),
+            RData::Unknown(data) =>
+            {
+                if is_parsed_type(self.rtype)
+                {
+                    return Err(DnsSdError::InvalidRecord);
+                }
+
+                if uncompressed_rdata(data, 0, data.len(), self.rtype).ok().as_ref() != Some(data)
+                {
+                    return Err(DnsSdError::InvalidRecord);
+                }
+
+                (self.rtype, DnsSdResponse::UnknownAnswer(UnknownAnswer { label, rtype: self.rtype, data: data.clone(), class, ttl, cache_flush }))
+            }
+        };
+
+        if rtype != self.rtype
+        {
+            return Err(DnsSdError::InvalidRecord);
+        }
+
+        Ok(response)
+    }
+}
+
+impl From<DnsSdResponse> for Record
+{
+    fn from(response: DnsSdResponse) -> Self
+    {
+        let rtype = response.rtype();
+        match response
+        {
+            DnsSdResponse::AAnswer(answer) => Record { name: answer.label, rtype, class: answer.class, cache_flush: answer.cache_flush, ttl: answer.ttl, rdata: RData::A(answer.address) },
+            DnsSdResponse::AaaaAnswer(answer) => Record { name: answer.label, rtype, class: answer.class, cache_flush: answer.cache_flush, ttl: answer.ttl, rdata: RData::Aaaa(answer.address) },
+            DnsSdResponse::PtrAnswer(answer) => Record { name: answer.label, rtype, class: answer.class, cache_flush: answer.cache_flush, ttl: answer.ttl, rdata: RData::Ptr(answer.service) },
+            DnsSdResponse::SrvAnswer(answer) => Record
+            {
+                name: answer.label,
+                rtype,
+                class: answer.class,
+                cache_flush: answer.cache_flush,
+                ttl: answer.ttl,
+                rdata: RData::Srv { priority: answer.priority, weight: answer.weight, port: answer.port, target: answer.service }
+            },
+            DnsSdResponse::TxtAnswer(answer) => Record { name: answer.label, rtype, class: answer.class, cache_flush: answer.cache_flush, ttl: answer.ttl, rdata: RData::Txt(answer.records) },
+            DnsSdResponse::UnknownAnswer(answer) => Record { name: answer.label, rtype, class: answer.class, cache_flush: answer.cache_flush, ttl: answer.ttl, rdata: RData::Unknown(answer.data) }
+        }
+    }
+}
+
+fn is_parsed_type(rtype: u16) -> bool
+{
+    matches!(Type::from(rtype), Ok(Type::A | Type::AAAA | Type::PTR | Type::SRV | Type::TXT))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use proptest::prelude::*;
+
+    fn record(rtype: u16, rdata: RData) -> Record
+    {
+        Record { name: String::from("gw2.local"), rtype, class: 1, cache_flush: true, ttl: 120, rdata }
+    }
+
+    #[test]
+    fn test_invalid_records()
+    {
+        assert!(Record::encode(&[record(1, RData::A(Ipv4Addr::new(192, 168, 1, 2)))]).is_ok());
+        assert_eq!(Record::encode(&[record(28, RData::A(Ipv4Addr::new(192, 168, 1, 2)))]), Err(DnsSdError::InvalidRecord));
+        assert_eq!(Record::encode(&[record(1, RData::Unknown(vec![192, 168, 1, 2]))]), Err(DnsSdError::InvalidRecord));
+
+        let mut flushed_class = record(1, RData::A(Ipv4Addr::new(192, 168, 1, 2)));
+        flushed_class.class = 0x8001;
+        assert_eq!(Record::encode(&[flushed_class]), Err(DnsSdError::InvalidRecord));
+
+        // A compressed name would point into the packet it came from.
+        assert_eq!(Record::encode(&[record(47, RData::Unknown(vec![0xc0, 0x0c, 0x00, 0x04, 0x40, 0x00, 0x00, 0x08]))]), Err(DnsSdError::InvalidRecord));
+        assert!(Record::encode(&[record(47, RData::Unknown(nsec_data("gw2.local", &[0x00, 0x04, 0x40, 0x00, 0x00, 0x08])))]).is_ok());
+    }
+
+    #[test]
+    fn test_compressed_nsec_round_trip()
+    {
+        // A legacy unicast response, the question moves every name once it is encoded without it.
+        let packet: [u8; 96] =
+        [
+            0x00, 0x00, 0x84, 0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x02, 0x04, 0x5f, 0x68, 0x61,
+            0x70, 0x04, 0x5f, 0x74, 0x63, 0x70, 0x05, 0x6c, 0x6f, 0x63, 0x61, 0x6c, 0x00, 0x00, 0x0c, 0x00,
+            0x01, 0xc0, 0x0c, 0x00, 0x0c, 0x00, 0x01, 0x00, 0x00, 0x11, 0x94, 0x00, 0x0b, 0x08, 0x44, 0x49,
+            0x52, 0x49, 0x47, 0x45, 0x52, 0x41, 0xc0, 0x0c, 0x03, 0x67, 0x77, 0x32, 0xc0, 0x16, 0x00, 0x01,
+            0x80, 0x01, 0x00, 0x00, 0x00, 0x78, 0x00, 0x04, 0xc0, 0xa8, 0x01, 0x02, 0xc0, 0x38, 0x00, 0x2f,
+            0x80, 0x01, 0x00, 0x00, 0x00, 0x78, 0x00, 0x08, 0xc0, 0x38, 0x00, 0x04, 0x40, 0x00, 0x00, 0x08
+        ];
+
+        let records = Record::parse(&packet).unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[2].rdata, RData::Unknown(nsec_data("gw2.local", &[0x00, 0x04, 0x40, 0x00, 0x00, 0x08])));
+
+        let encoded = Record::encode(&records).unwrap();
+        assert_eq!(Record::parse(&encoded).unwrap(), records);
+        assert_eq!(Record::encode(&Record::parse(&encoded).unwrap()).unwrap(), encoded);
+    }
+
+    // NSEC data with an uncompressed next name followed by the type bitmaps.
+    fn nsec_data(next: &str, bitmaps: &[u8]) -> Vec<u8>
+    {
+        let mut data = Vec::new();
+        for label in next.split('.').filter(|label| !label.is_empty())
+        {
+            data.push(label.len() as u8);
+            data.extend_from_slice(label.as_bytes());
+        }
+        data.push(0x00);
+        data.extend_from_slice(bitmaps);
+        data
+    }
+
+    // Labels of up to 60 bytes without dots, names of up to four labels stay within 253 bytes.
+    fn name() -> impl Strategy<Value = String>
+    {
+        prop::collection::vec("[^.]{1,15}", 0..4).prop_map(|labels| labels.join("."))
+    }
+
+    fn rdata() -> impl Strategy<Value = (u16, RData)>
+    {
+        prop_oneof![
+            any::<[u8; 4]>().prop_map(|octets| (Type::A as u16, RData::A(Ipv4Addr::from(octets)))),
+            any::<[u8; 16]>().prop_map(|octets| (Type::AAAA as u16, RData::Aaaa(Ipv6Addr::from(octets)))),
+            name().prop_map(|target| (Type::PTR as u16, RData::Ptr(target))),
+            (any::<u16>(), any::<u16>(), any::<u16>(), name())
+                .prop_map(|(priority, weight, port, target)| (Type::SRV as u16, RData::Srv { priority, weight, port, target })),
+            prop::collection::vec("(?s).{0,40}", 0..4).prop_map(|records| (Type::TXT as u16, RData::Txt(records))),
+            // Any data for types that carry no names, names uncompressed for NSEC.
+            (any::<u16>().prop_filter("known type", |rtype| Type::from(*rtype).is_err()), prop::collection::vec(any::<u8>(), 0..64))
+                .prop_map(|(rtype, data)| (rtype, RData::Unknown(data))),
+            (name(), prop::collection::vec(any::<u8>(), 0..32))
+                .prop_map(|(next, bitmaps)| (Type::NSEC as u16, RData::Unknown(nsec_data(&next, &bitmaps))))
+        ]
+    }
+
+    fn records() -> impl Strategy<Value = Vec<Record>>
+    {
+        let record = (name(), rdata(), 0..CLASS_CACHE_FLUSH_MASK, any::<bool>(), any::<u32>())
+            .prop_map(|(name, (rtype, rdata), class, cache_flush, ttl)| Record { name, rtype, class, cache_flush, ttl, rdata });
+
+        prop::collection::vec(record, 1..8)
+    }
+
+    proptest!
+    {
+        #[test]
+        fn test_round_trip(records in records())
+        {
+            let packet = Record::encode(&records).unwrap();
+            prop_assert_eq!(Record::parse(&packet).unwrap(), records);
+
+            // Encoding what was parsed gives the same packet again.
+            prop_assert_eq!(Record::encode(&Record::parse(&packet).unwrap()).unwrap(), packet);
+        }
+    }
+}
@@ -0,0 +1,357 @@
+use std::collections::{ HashMap, HashSet };
+use std::time::{ Duration, Instant };
+use log::debug;
+
+use crate::dns::DnsSdResponse;
+
+// Bytes the cache may use by default, enough for several hundred typical records.
+pub const DEFAULT_MAX_SIZE: usize = 256 * 1024;
+
+// Records that said goodbye or were flushed are kept for one more second, RFC 6762 section 10.1
+// and 10.2.
+const EVICTION_DELAY: Duration = Duration::from_secs(1);
+
+// Records are cached per interface, RFC 6762 section 10.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct RecordKey
+{
+    name: String,
+    rtype: u16,
+    class: u16,
+    rdata: Vec<u8>,
+    interface: u32
+}
+
+impl RecordKey
+{
+    pub fn from(response: &DnsSdResponse, interface: u32) -> RecordKey
+    {
+        RecordKey
+        {
+            name: response.label().to_ascii_lowercase(),
+            rtype: response.rtype(),
+            class: response.class(),
+            rdata: response.rdata_key(),
+            interface
+        }
+    }
+}
+
+pub struct CachedRecord
+{
+    pub response: DnsSdResponse,
+    // Index of the interface the record arrived on.
+    pub interface: u32,
+    pub received: Instant,
+    pub expires: Instant,
+    // Set by a goodbye for the record, until it is evicted or received again.
+    pub goodbye: bool,
+    // Bytes the record takes in the cache.
+    size: usize
+}
+
+impl CachedRecord
+{
+    fn new(response: DnsSdResponse, key: &RecordKey, now: Instant) -> CachedRecord
+    {
+        let expires = now + Duration::from_secs(response.ttl() as u64);
+
+        // Roughly what the record takes. The key is stored twice, in the records and in the index
+        // by name, and the record holds the name and data once more.
+        let size = 2 * std::mem::size_of::<RecordKey>() + std::mem::size_of::<CachedRecord>() + 3 * (key.name.len() + key.rdata.len());
+
+        CachedRecord
+        {
+            response,
+            interface: key.interface,
+            received: now,
+            expires,
+            goodbye: false,
+            size
+        }
+    }
+
+    fn expire_soon(&mut self, now: Instant)
+    {
+        self.expires = self.expires.min(now + EVICTION_DELAY);
+    }
+}
+
+// Record cache keyed by name, type, class and data. Inserting a record that is already cached only
+// refreshes it, and the bytes used by the records are capped so a busy network can't grow it
+// without bound.
+pub struct RecordCache
+{
+    records: HashMap<RecordKey, CachedRecord>,
+    names: HashMap<String, HashSet<RecordKey>>,
+    size: usize,
+    max_size: usize
+}
+
+impl RecordCache
+{
+    pub fn new(max_size: usize) -> RecordCache
+    {
+        RecordCache
+        {
+            records: HashMap::new(),
+            names: HashMap::new(),
+            size: 0,
+            max_size
+        }
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize
+    {
+        self.records.len()
+    }
+
+    pub fn set_max_size(&mut self, max_size: usize)
+    {
+        self.max_size = max_size;
+        while self.size > self.max_size
+        {
+            self.evict_one();
+        }
+    }
+
+    pub fn insert(&mut self, response: DnsSdResponse, interface: u32, now: Instant)
+    {
+        let key = RecordKey::from(&response, interface);
+        let record = CachedRecord::new(response, &key, now);
+
+        // The size depends on the key only, so a refreshed record takes as much as before.
+        if let Some(cached) = self.records.get_mut(&key)
+        {
+            *cached = record;
+            return;
+        }
+
+        if record.size > self.max_size
+        {
+            debug!("Record too large to cache: {:?}", record.response);
+            return;
+        }
+
+        if self.size + record.size > self.max_size
+        {
+            self.remove_expired(now);
+        }
+
+        while !self.records.is_empty() && self.size + record.size > self.max_size
+        {
+            self.evict_one();
+        }
+
+        self.size += record.size;
+        self.names.entry(key.name.clone()).or_default().insert(key.clone());
+        self.records.insert(key, record);
+    }
+
+    // A goodbye record makes the cached copy of the same record expire one second later.
+    pub fn goodbye(&mut self, response: &DnsSdResponse, interface: u32, now: Instant)
+    {
+        if let Some(cached) = self.records.get_mut(&RecordKey::from(response, interface))
+        {
+            cached.expire_soon(now);
+            cached.goodbye = true;
+        }
+    }
+
+    // Other records of the same name, type and class received on the interface more than a second
+    // ago expire one second later, see RFC 6762 section 10.2.
+    pub fn flush_rrset(&mut self, response: &DnsSdResponse, interface: u32, now: Instant)
+    {
+        let key = RecordKey::from(response, interface);
+        let Some(keys) = self.names.get(&key.name) else
+        {
+            return;
+        };
+
+        for other in keys
+        {
+            if other.rtype != key.rtype || other.class != key.class || other.interface != key.interface || other.rdata == key.rdata
+            {
+                continue;
+            }
+
+            if let Some(cached) = self.records.get_mut(other)
+            {
+                if now.saturating_duration_since(cached.received) >= EVICTION_DELAY
+                {
+                    cached.expire_soon(now);
+                }
+            }
+        }
+    }
+
+    pub fn remove_expired(&mut self, now: Instant)
+    {
+        let expired: Vec<RecordKey> = self.records.iter()
+            .filter(|(_, cached)| cached.expires <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in expired
+        {
+            debug!("Record expired: {:?}", self.records[&key].response);
+            self.remove(&key);
+        }
+    }
+
+    // Drop everything received on an interface that went away.
+    pub fn remove_interface(&mut self, interface: u32)
+    {
+        let keys: Vec<RecordKey> = self.records.keys()
+            .filter(|key| key.interface == interface)
+            .cloned()
+            .collect();
+
+        for key in keys
+        {
+            self.remove(&key);
+        }
+    }
+
+    pub fn get(&self, name: &str) -> impl Iterator<Item = &CachedRecord>
+    {
+        self.names.get(&name.to_ascii_lowercase())
+            .into_iter()
+            .flatten()
+            .filter_map(|key| self.records.get(key))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &CachedRecord>
+    {
+        self.records.values()
+    }
+
+    // Evict the record closest to expiring, it is the least useful one to keep.
+    fn evict_one(&mut self)
+    {
+        let key = self.records.iter()
+            .min_by_key(|(_, cached)| cached.expires)
+            .map(|(key, _)| key.clone());
+
+        if let Some(key) = key
+        {
+            debug!("Cache full, evicting: {:?}", self.records[&key].response);
+            self.remove(&key);
+        }
+    }
+
+    fn remove(&mut self, key: &RecordKey)
+    {
+        if let Some(cached) = self.records.remove(key)
+        {
+            self.size -= cached.size;
+        }
+
+        if let Some(keys) = self.names.get_mut(&key.name)
+        {
+            keys.remove(key);
+            if keys.is_empty()
+            {
+                self.names.remove(&key.name);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::dns::{ AAnswer, SrvAnswer, TxtAnswer };
+    use std::net::Ipv4Addr;
+
+    fn a_answer(label: &str, last_octet: u8, ttl: u32) -> DnsSdResponse
+    {
+        DnsSdResponse::AAnswer(AAnswer { label: String::from(label), address: Ipv4Addr::new(192, 168, 1, last_octet), class: 1, ttl, cache_flush: true })
+    }
+
+    #[test]
+    fn test_insert_deduplicates()
+    {
+        let mut cache = RecordCache::new(DEFAULT_MAX_SIZE);
+        let now = Instant::now();
+
+        cache.insert(a_answer("host.local", 2, 120), 1, now);
+        cache.insert(a_answer("HOST.local", 2, 120), 1, now + Duration::from_secs(1));
+        cache.insert(a_answer("host.local", 3, 120), 1, now + Duration::from_secs(1));
+
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get("host.local").count(), 2);
+        assert!(cache.get("host.local").all(|cached| cached.received == now + Duration::from_secs(1)));
+
+        // The same record from another interface is cached on its own.
+        cache.insert(a_answer("host.local", 2, 120), 2, now + Duration::from_secs(2));
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.get("host.local").filter(|cached| cached.interface == 2).count(), 1);
+
+        cache.remove_interface(1);
+        assert_eq!(cache.len(), 1);
+        assert!(cache.iter().all(|cached| cached.interface == 2));
+    }
+
+    #[test]
+    fn test_insert_respects_max_size()
+    {
+        let now = Instant::now();
+        let size = CachedRecord::new(a_answer("host.local", 1, 10), &RecordKey::from(&a_answer("host.local", 1, 10), 1), now).size;
+        let mut cache = RecordCache::new(3 * size);
+
+        cache.insert(a_answer("host.local", 1, 10), 1, now);
+        cache.insert(a_answer("host.local", 2, 120), 1, now);
+        cache.insert(a_answer("host.local", 3, 120), 1, now);
+        cache.insert(a_answer("host.local", 4, 120), 1, now);
+
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.size, 3 * size);
+        assert!(cache.iter().all(|cached| cached.response.ttl() == 120));
+
+        // A large record takes the room of several small ones.
+        let txt_answer = DnsSdResponse::TxtAnswer(TxtAnswer { label: String::from("host.local"), records: vec![String::from("x").repeat(size / 2)], class: 1, ttl: 120, cache_flush: true });
+        cache.insert(txt_answer, 1, now);
+        assert_eq!(cache.len(), 1);
+        assert!(cache.size <= 3 * size);
+
+        cache.set_max_size(size);
+        assert_eq!(cache.len(), 0);
+        assert_eq!(cache.size, 0);
+        cache.insert(a_answer("host.local", 5, 120), 1, now);
+        assert_eq!(cache.get("host.local").count(), 1);
+
+        cache.set_max_size(0);
+        cache.insert(a_answer("host.local", 6, 120), 1, now);
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[test]
+    fn test_goodbye_and_flush()
+    {
+        let mut cache = RecordCache::new(DEFAULT_MAX_SIZE);
+        let now = Instant::now();
+
+        let srv_answer = DnsSdResponse::SrvAnswer(SrvAnswer { label: String::from("A._hap._tcp.local"), service: String::from("host.local"), priority: 0, weight: 0, port: 80, class: 1, ttl: 120, cache_flush: true });
+        cache.insert(srv_answer.clone(), 1, now);
+        cache.insert(a_answer("host.local", 2, 120), 1, now);
+        cache.insert(a_answer("host.local", 4, 120), 2, now);
+        cache.insert(a_answer("host.local", 3, 120), 1, now + Duration::from_secs(5));
+
+        cache.goodbye(&srv_answer, 1, now + Duration::from_secs(10));
+        assert!(cache.get("A._hap._tcp.local").all(|cached| cached.goodbye));
+        assert!(cache.get("host.local").all(|cached| !cached.goodbye));
+        cache.flush_rrset(&a_answer("host.local", 3, 120), 1, now + Duration::from_secs(5));
+
+        // The record on the other interface is not flushed.
+        cache.remove_expired(now + Duration::from_secs(7));
+        assert_eq!(cache.len(), 3);
+        assert!(cache.get("host.local").all(|cached| matches!(&cached.response, DnsSdResponse::AAnswer(answer) if answer.address.octets()[3] == 3 || cached.interface == 2)));
+
+        cache.remove_expired(now + Duration::from_secs(11));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get("A._hap._tcp.local").count(), 0);
+    }
+}
@@ -0,0 +1,485 @@
+use std::net::IpAddr;
+use std::sync::mpsc;
+use std::time::{ Duration, Instant };
+use log::debug;
+
+use crate::dns::DnsSdResponse;
+use crate::service_registration::{ RegistrationEvent, ServiceRegistration };
+
+// Probes sent for a name before it is taken and the time between them, RFC 6762 section 8.1.
+const PROBE_COUNT: u32 = 3;
+const PROBE_INTERVAL: Duration = Duration::from_millis(250);
+
+// Wait before probing again after losing a tie-break, RFC 6762 section 8.2.
+const TIEBREAK_DELAY: Duration = Duration::from_secs(1);
+
+// Announcements sent once a name is taken, the interval doubles after each, RFC 6762 section 8.3.
+const ANNOUNCE_COUNT: u32 = 2;
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Conflict
+{
+    Instance,
+    Host
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State
+{
+    Probing { sent: u32, next: Instant },
+    Announcing { sent: u32, next: Instant },
+    Registered
+}
+
+impl State
+{
+    fn is_probing(&self) -> bool
+    {
+        matches!(self, State::Probing { .. })
+    }
+}
+
+// Registrations to send probes or announcements for.
+#[derive(Debug, Default, PartialEq)]
+pub struct Due
+{
+    pub probes: Vec<ServiceRegistration>,
+    pub announcements: Vec<ServiceRegistration>
+}
+
+struct Entry
+{
+    registration: ServiceRegistration,
+    state: State,
+    // The caller of register, waiting for the name probing ends with.
+    waiter: Option<mpsc::Sender<ServiceRegistration>>,
+    // Full name before a conflict that showed up after registering, reported when probing ends.
+    renamed_from: Option<String>,
+    // Announced registration this one replaced, whose records may still come back to us.
+    replaced: Option<ServiceRegistration>
+}
+
+// Registered services of a responder and the probing of their names.
+pub struct Registry
+{
+    entries: Vec<Entry>,
+    subscribers: Vec<mpsc::Sender<RegistrationEvent>>
+}
+
+impl Registry
+{
+    pub fn new() -> Registry
+    {
+        Registry
+        {
+            entries: Vec::new(),
+            subscribers: Vec::new()
+        }
+    }
+
+    pub fn subscribe(&mut self) -> mpsc::Receiver<RegistrationEvent>
+    {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    // Start probing for a registration, replacing one with the same full name. The final
+    // registration is sent to the waiter once probing is done.
+    pub fn add(&mut self, registration: ServiceRegistration, waiter: mpsc::Sender<ServiceRegistration>, first_probe: Instant)
+    {
+        let replaced = self.remove(&registration.full_name());
+        self.entries.push(Entry
+        {
+            registration,
+            state: State::Probing { sent: 0, next: first_probe },
+            waiter: Some(waiter),
+            renamed_from: None,
+            replaced
+        });
+    }
+
+    // Remove a registration. Returns it if it was announced and needs a goodbye.
+    pub fn remove(&mut self, instance: &str) -> Option<ServiceRegistration>
+    {
+        let position = self.entries.iter().position(|entry| entry.registration.full_name().eq_ignore_ascii_case(instance))?;
+        let entry = self.entries.remove(position);
+
+        (!entry.state.is_probing()).then_some(entry.registration)
+    }
+
+    // Remove all registrations, returning those that were announced.
+    pub fn remove_all(&mut self) -> Vec<ServiceRegistration>
+    {
+        self.entries.drain(..)
+            .filter(|entry| !entry.state.is_probing())
+            .map(|entry| entry.registration)
+            .collect()
+    }
+
+    // All registrations, also those still probing.
+    pub fn registrations(&self) -> Vec<ServiceRegistration>
+    {
+        self.entries.iter().map(|entry| entry.registration.clone()).collect()
+    }
+
+    // Registrations done probing, the only ones queries are answered for.
+    pub fn registered(&self) -> Vec<ServiceRegistration>
+    {
+        self.entries.iter()
+            .filter(|entry| !entry.state.is_probing())
+            .map(|entry| entry.registration.clone())
+            .collect()
+    }
+
+    // Registrations to send a probe or an announcement for now. Registrations that sent all their
+    // probes without a conflict are announced next.
+    pub fn step(&mut self, now: Instant) -> Due
+    {
+        let mut due = Due::default();
+        let mut events = Vec::new();
+
+        for entry in self.entries.iter_mut()
+        {
+            if let State::Probing { sent, next } = entry.state
+            {
+                if next > now
+                {
+                    continue;
+                }
+
+                if sent < PROBE_COUNT
+                {
+                    entry.state = State::Probing { sent: sent + 1, next: now + PROBE_INTERVAL };
+                    due.probes.push(entry.registration.clone());
+                    continue;
+                }
+
+                debug!("Registered service instance: {}", entry.registration.full_name());
+                entry.state = State::Announcing { sent: 0, next: now };
+
+                let renamed_from = entry.renamed_from.take();
+                if let Some(waiter) = entry.waiter.take()
+                {
+                    let _ = waiter.send(entry.registration.clone());
+                }
+                else if let Some(previous) = renamed_from
+                {
+                    events.push(RegistrationEvent::Renamed { previous, registration: entry.registration.clone() });
+                }
+            }
+
+            if let State::Announcing { sent, next } = entry.state
+            {
+                if next > now
+                {
+                    continue;
+                }
+
+                entry.state = if sent + 1 < ANNOUNCE_COUNT
+                {
+                    State::Announcing { sent: sent + 1, next: now + ANNOUNCE_INTERVAL * 2u32.pow(sent) }
+                }
+                else
+                {
+                    State::Registered
+                };
+                due.announcements.push(entry.registration.clone());
+            }
+        }
+
+        for event in events
+        {
+            self.subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+        }
+
+        due
+    }
+
+    // Time until the next probe, announcement or the end of probing.
+    pub fn time_to_next(&self, now: Instant) -> Option<Duration>
+    {
+        self.entries.iter()
+            .filter_map(|entry| match entry.state
+            {
+                State::Probing { next, .. } | State::Announcing { next, .. } => Some(next.saturating_duration_since(now)),
+                State::Registered => None
+            })
+            .min()
+    }
+
+    // Look for records in a response received on an interface with the given addresses that
+    // conflict with our names, and rename and probe again. A record conflicts when it has one of
+    // our names and is not one of our records. Once registered, only records with the cache flush
+    // bit count. Returns whether a registration was renamed.
+    pub fn handle_response(&mut self, records: &[DnsSdResponse], addresses: &[IpAddr], now: Instant) -> bool
+    {
+        let mut renamed = false;
+        for entry in self.entries.iter_mut()
+        {
+            let probing = entry.state.is_probing();
+            let replaced = entry.replaced.as_ref();
+            let conflict = records.iter()
+                .filter(|record| probing || record.cache_flush())
+                .filter(|record| match replaced
+                {
+                    Some(replaced) => conflict_with(replaced, record, addresses).is_some(),
+                    None => true
+                })
+                .find_map(|record| conflict_with(&entry.registration, record, addresses));
+
+            let Some(conflict) = conflict else
+            {
+                continue;
+            };
+
+            let previous = entry.registration.full_name();
+            match conflict
+            {
+                Conflict::Instance => entry.registration.rename_instance(),
+                Conflict::Host => entry.registration.rename_host()
+            }
+            debug!("Name conflict for {}, probing as {} on {}", previous, entry.registration.full_name(), entry.registration.host);
+
+            if entry.waiter.is_none() && entry.renamed_from.is_none()
+            {
+                entry.renamed_from = Some(previous);
+            }
+
+            entry.state = State::Probing { sent: 0, next: now };
+            renamed = true;
+        }
+
+        renamed
+    }
+
+    // Compare the authority records of a probe from another host with ours for every name we are
+    // probing for, RFC 6762 section 8.2. Registrations that lose probe again a second later.
+    // Returns whether a registration lost.
+    pub fn handle_probe(&mut self, authorities: &[DnsSdResponse], addresses: &[IpAddr], now: Instant) -> bool
+    {
+        let mut lost = false;
+        for entry in self.entries.iter_mut()
+        {
+            if !entry.state.is_probing()
+            {
+                continue;
+            }
+
+            let ours = entry.registration.probe_records(addresses);
+            let loses = entry.registration.unique_names().iter().any(|name|
+            {
+                let ours: Vec<&DnsSdResponse> = ours.iter().filter(|record| record.label().eq_ignore_ascii_case(name)).collect();
+                let theirs: Vec<&DnsSdResponse> = authorities.iter().filter(|record| record.label().eq_ignore_ascii_case(name)).collect();
+
+                !theirs.is_empty() && loses_tiebreak(&ours, &theirs)
+            });
+
+            if loses
+            {
+                debug!("Lost probe tie-break for {}", entry.registration.full_name());
+                entry.state = State::Probing { sent: 0, next: now + TIEBREAK_DELAY };
+                lost = true;
+            }
+        }
+
+        lost
+    }
+}
+
+fn conflict_with(registration: &ServiceRegistration, record: &DnsSdResponse, addresses: &[IpAddr]) -> Option<Conflict>
+{
+    let (ours, conflict) = if record.label().eq_ignore_ascii_case(&registration.full_name())
+    {
+        (vec![registration.srv_record(), registration.txt_record()], Conflict::Instance)
+    }
+    else if record.label().eq_ignore_ascii_case(&registration.host)
+    {
+        (registration.address_records(addresses), Conflict::Host)
+    }
+    else
+    {
+        return None;
+    };
+
+    if ours.iter().any(|our| our.is_same_record(record))
+    {
+        return None;
+    }
+
+    Some(conflict)
+}
+
+// Records are sorted by class, type and data and compared in turn. The first difference decides,
+// if there is none the host with more records wins. Identical records are no conflict.
+fn loses_tiebreak(ours: &[&DnsSdResponse], theirs: &[&DnsSdResponse]) -> bool
+{
+    let sorted = |records: &[&DnsSdResponse]|
+    {
+        let mut keys: Vec<(u16, u16, Vec<u8>)> = records.iter()
+            .map(|record| (record.class(), record.rtype(), record.rdata().unwrap_or_default()))
+            .collect();
+        keys.sort();
+        keys
+    };
+
+    sorted(ours) < sorted(theirs)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::net::Ipv4Addr;
+    use crate::dns::SrvAnswer;
+
+    fn registration() -> ServiceRegistration
+    {
+        ServiceRegistration::new("Living Room", "_http._tcp", "firmware.local", 80)
+    }
+
+    fn addresses() -> Vec<IpAddr>
+    {
+        vec![IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20))]
+    }
+
+    fn srv(instance: &str, port: u16) -> DnsSdResponse
+    {
+        DnsSdResponse::SrvAnswer(SrvAnswer { label: String::from(instance), service: String::from("other.local"), priority: 0, weight: 0, port, class: 1, ttl: 120, cache_flush: true })
+    }
+
+    // Step through probing in steps of the probe interval and return the number of probes sent.
+    fn probe(registry: &mut Registry, now: &mut Instant) -> usize
+    {
+        let mut probes = 0;
+        for _ in 0..PROBE_COUNT + 1
+        {
+            probes += registry.step(*now).probes.len();
+            *now += PROBE_INTERVAL;
+        }
+
+        probes
+    }
+
+    #[test]
+    fn test_probing()
+    {
+        let mut registry = Registry::new();
+        let (waiter, registered) = mpsc::channel();
+        let mut now = Instant::now();
+
+        registry.add(registration(), waiter, now + Duration::from_millis(100));
+        assert_eq!(registry.step(now), Due::default());
+        assert_eq!(registry.time_to_next(now), Some(Duration::from_millis(100)));
+
+        now += Duration::from_millis(100);
+        assert_eq!(probe(&mut registry, &mut now), 3);
+        assert_eq!(registry.registered(), vec![registration()]);
+        assert_eq!(registered.try_recv(), Ok(registration()));
+    }
+
+    #[test]
+    fn test_announcements()
+    {
+        let mut registry = Registry::new();
+        let (waiter, _registered) = mpsc::channel();
+        let now = Instant::now();
+
+        registry.add(registration(), waiter, now);
+        for probe in 0..PROBE_COUNT
+        {
+            assert_eq!(registry.step(now + PROBE_INTERVAL * probe).probes.len(), 1);
+        }
+
+        // Announced right after the last probe interval and once more a second later.
+        let registered = now + PROBE_INTERVAL * PROBE_COUNT;
+        assert_eq!(registry.step(registered), Due { probes: Vec::new(), announcements: vec![registration()] });
+        assert_eq!(registry.time_to_next(registered), Some(ANNOUNCE_INTERVAL));
+        assert_eq!(registry.step(registered + Duration::from_millis(999)), Due::default());
+        assert_eq!(registry.step(registered + ANNOUNCE_INTERVAL).announcements, vec![registration()]);
+        assert_eq!(registry.time_to_next(registered + ANNOUNCE_INTERVAL), None);
+
+        // Only announced registrations need a goodbye.
+        let (waiter, _registered) = mpsc::channel();
+        registry.add(ServiceRegistration::new("Kitchen", "_http._tcp", "firmware.local", 80), waiter, now);
+        assert_eq!(registry.remove("kitchen._http._tcp.local"), None);
+        assert_eq!(registry.remove("living room._http._tcp.local"), Some(registration()));
+        assert!(registry.remove_all().is_empty());
+    }
+
+    #[test]
+    fn test_conflict_while_probing()
+    {
+        let mut registry = Registry::new();
+        let (waiter, registered) = mpsc::channel();
+        let mut now = Instant::now();
+
+        registry.add(registration(), waiter, now);
+        registry.step(now);
+
+        // Our own records coming back are not a conflict, a shared record of the name is.
+        let ours = registration().probe_records(&addresses());
+        assert!(!registry.handle_response(&ours, &addresses(), now));
+
+        let mut other = srv("Living Room._http._tcp.local", 8080);
+        if let DnsSdResponse::SrvAnswer(srv_answer) = &mut other
+        {
+            srv_answer.cache_flush = false;
+        }
+        assert!(registry.handle_response(&[other], &addresses(), now));
+        assert!(registry.registered().is_empty());
+
+        assert_eq!(probe(&mut registry, &mut now), 3);
+        let renamed = registered.try_recv().unwrap();
+        assert_eq!(renamed.full_name(), "Living Room (2)._http._tcp.local");
+        assert_eq!(registry.registered(), vec![renamed.clone()]);
+
+        // Announcements of the registration a new one replaces are ours too.
+        let (waiter, _registered) = mpsc::channel();
+        let mut moved = renamed.clone();
+        moved.port = 8080;
+        registry.add(moved, waiter, now);
+        assert!(!registry.handle_response(&renamed.announce_records(&addresses()), &addresses(), now));
+    }
+
+    #[test]
+    fn test_conflict_after_registering()
+    {
+        let mut registry = Registry::new();
+        let events = registry.subscribe();
+        let (waiter, _registered) = mpsc::channel();
+        let mut now = Instant::now();
+
+        registry.add(registration(), waiter, now);
+        probe(&mut registry, &mut now);
+
+        let other_address = DnsSdResponse::AAnswer(crate::dns::AAnswer { label: String::from("firmware.local"), address: Ipv4Addr::new(192, 168, 1, 99), class: 1, ttl: 120, cache_flush: true });
+        assert!(registry.handle_response(&[other_address], &addresses(), now));
+        assert!(registry.registered().is_empty());
+
+        assert_eq!(probe(&mut registry, &mut now), 3);
+        let mut expected = registration();
+        expected.rename_host();
+        assert_eq!(events.try_recv(), Ok(RegistrationEvent::Renamed { previous: registration().full_name(), registration: expected }));
+    }
+
+    #[test]
+    fn test_tiebreak()
+    {
+        let mut registry = Registry::new();
+        let (waiter, _registered) = mpsc::channel();
+        let now = Instant::now();
+
+        registry.add(registration(), waiter, now);
+        registry.step(now);
+
+        // Identical records or records that sort before ours don't make us lose.
+        assert!(!registry.handle_probe(&registration().probe_records(&addresses()), &addresses(), now));
+        assert!(!registry.handle_probe(&[registration().txt_record(), srv("living room._http._tcp.local", 79)], &addresses(), now));
+
+        assert!(registry.handle_probe(&[registration().txt_record(), srv("living room._http._tcp.local", 81)], &addresses(), now));
+        assert_eq!(registry.step(now + Duration::from_millis(999)), Due::default());
+        assert_eq!(registry.step(now + TIEBREAK_DELAY).probes.len(), 1);
+    }
+}
@@ -0,0 +1,784 @@
+use std::net::{ IpAddr, SocketAddr, UdpSocket };
+use std::sync::{ Arc, Mutex, mpsc, atomic::{ AtomicBool, Ordering } };
+use std::thread;
+use std::time::{ Duration, Instant };
+use log::debug;
+
+use crate::dnssd_error::DnsSdError;
+use crate::dns::{ new_probe, new_response, DnsSdQuery, DnsSdResponse, Type };
+use crate::interface::{ self, InterfaceSelection, NetworkInterface };
+use crate::registry::Registry;
+use crate::service_registration::{ RegistrationEvent, ServiceRegistration };
+use crate::socket::{ create_multicast_sockets, multicast_addr_for, MULTICAST_PORT };
+use crate::IpType;
+
+// How often the listen threads wake up without traffic to see if they should stop.
+const LISTEN_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Highest TTL in responses to legacy resolvers, RFC 6762 section 6.7.
+const LEGACY_UNICAST_TTL: u32 = 10;
+
+// Answers queries for registered services, on sockets joined to the mDNS multicast group.
+pub struct ServiceResponder
+{
+    registry: Arc<Mutex<Registry>>,
+    links: Arc<Vec<Link>>,
+    listeners: Vec<Listener>,
+    probe_thread: Option<thread::JoinHandle<Result<(), DnsSdError>>>,
+    // Wakes up the probe thread, dropping the channel stops it.
+    wake_prober: Option<mpsc::Sender<()>>
+}
+
+// A thread answering queries received on one interface and IP version.
+struct Listener
+{
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<Result<(), DnsSdError>>>
+}
+
+// A socket joined to the multicast group on one interface and IP version, with the addresses the
+// host records of that interface point to.
+struct Link
+{
+    socket: UdpSocket,
+    multicast_addr: SocketAddr,
+    addresses: Vec<IpAddr>
+}
+
+impl Listener
+{
+    fn start(socket: UdpSocket, addresses: Vec<IpAddr>, registry: Arc<Mutex<Registry>>, wake_prober: mpsc::Sender<()>) -> Result<Listener, DnsSdError>
+    {
+        socket.set_read_timeout(Some(LISTEN_TIMEOUT))?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let thread = thread::spawn(move || respond(socket, addresses, registry, wake_prober, stopped));
+
+        Ok(Listener
+        {
+            stop,
+            thread: Some(thread)
+        })
+    }
+
+    fn stop(&mut self) -> Result<(), DnsSdError>
+    {
+        self.stop.store(true, Ordering::SeqCst);
+        join(self.thread.take())
+    }
+}
+
+impl ServiceResponder
+{
+    // Answer queries on the interface of the default route.
+    pub fn new(ip_type: IpType) -> Result<ServiceResponder, DnsSdError>
+    {
+        let interface = interface::default_interface()?;
+        ServiceResponder::on_interfaces(ip_type, vec![interface])
+    }
+
+    // Answer queries on the selected interfaces. Fails if a selection does not match any of the
+    // interfaces from list_interfaces.
+    pub fn with_interfaces(ip_type: IpType, selections: &[InterfaceSelection]) -> Result<ServiceResponder, DnsSdError>
+    {
+        let interfaces = interface::select_interfaces(selections)?;
+        ServiceResponder::on_interfaces(ip_type, interfaces)
+    }
+
+    // Port 5353 is shared with other mDNS software on the host, opening it fails if that is not
+    // possible.
+    fn on_interfaces(ip_type: IpType, interfaces: Vec<NetworkInterface>) -> Result<ServiceResponder, DnsSdError>
+    {
+        let mut links = Vec::new();
+        for interface in interfaces.iter()
+        {
+            for socket in create_multicast_sockets(&ip_type, interface)?
+            {
+                let multicast_addr = multicast_addr_for(&socket)?;
+                links.push(Link { socket, multicast_addr, addresses: interface_addresses(interface, &ip_type) });
+            }
+        }
+
+        if links.is_empty()
+        {
+            return Err(DnsSdError::InterfaceNotFound);
+        }
+
+        let links = Arc::new(links);
+        let registry = Arc::new(Mutex::new(Registry::new()));
+        let (wake_prober, woken) = mpsc::channel::<()>();
+
+        let mut responder = ServiceResponder
+        {
+            registry: registry.clone(),
+            links: links.clone(),
+            listeners: Vec::new(),
+            probe_thread: None,
+            wake_prober: Some(wake_prober.clone())
+        };
+
+        // Dropping the responder stops the listeners already started.
+        for link in links.iter()
+        {
+            let listener = Listener::start(link.socket.try_clone()?, link.addresses.clone(), registry.clone(), wake_prober.clone())?;
+            responder.listeners.push(listener);
+        }
+
+        responder.probe_thread = Some(thread::spawn(move || probe(links, registry, woken)));
+
+        Ok(responder)
+    }
+
+    // Probe for the instance name and host name of the registration and start answering queries
+    // for it. On a conflict the instance or host is renamed, e.g. to "Living Room (2)" or
+    // "firmware-2.local", and probed again. Returns the registration as it ended up. Conflicts that
+    // show up later are reported to subscribers. Registering an instance with the same full name
+    // again replaces it.
+    pub fn register(&mut self, registration: ServiceRegistration) -> Result<ServiceRegistration, DnsSdError>
+    {
+        if !registration.is_valid()
+        {
+            return Err(DnsSdError::LabelInvalid);
+        }
+
+        // Fail now rather than on every query if the names can't be encoded.
+        new_response(0, &[], &[registration.ptr_record(), registration.srv_record(), registration.txt_record()], &[])?;
+
+        debug!("Registering service instance: {}", registration.full_name());
+
+        // Hosts that start at the same time don't probe at once, RFC 6762 section 8.1.
+        let first_probe = Instant::now() + Duration::from_millis(fastrand::u64(0..250));
+        let (waiter, registered) = mpsc::channel();
+        self.registry.lock().unwrap().add(registration, waiter, first_probe);
+        self.wake_prober();
+
+        loop
+        {
+            match registered.recv_timeout(LISTEN_TIMEOUT)
+            {
+                Ok(registration) => return Ok(registration),
+                Err(mpsc::RecvTimeoutError::Timeout) if !self.probe_thread.as_ref().is_some_and(|thread| thread.is_finished()) => continue,
+                Err(_) => return Err(DnsSdError::ThreadPanicked)
+            }
+        }
+    }
+
+    // Stop answering queries for an instance, e.g. "Living Room._http._tcp.local", and send a
+    // goodbye for it if it was announced.
+    pub fn unregister(&mut self, instance: &str)
+    {
+        debug!("Unregistering service instance: {}", instance);
+
+        let (removed, remaining) =
+        {
+            let mut registry = self.registry.lock().unwrap();
+            (registry.remove(instance), registry.registrations())
+        };
+
+        if let Some(removed) = removed
+        {
+            send_goodbyes(&self.links, &[removed], &remaining);
+        }
+    }
+
+    // Registrations with the names they go by now.
+    pub fn get_registrations(&self) -> Vec<ServiceRegistration>
+    {
+        self.registry.lock().unwrap().registrations()
+    }
+
+    // Receive an event when a registration is renamed after a conflict.
+    pub fn subscribe(&self) -> mpsc::Receiver<RegistrationEvent>
+    {
+        self.registry.lock().unwrap().subscribe()
+    }
+
+    // Stop the probe and listen threads and report any error they ended with. Dropping the
+    // responder stops them as well, but errors are then only logged.
+    pub fn shutdown(&mut self) -> Result<(), DnsSdError>
+    {
+        let removed = self.registry.lock().unwrap().remove_all();
+        if !removed.is_empty()
+        {
+            send_goodbyes(&self.links, &removed, &[]);
+        }
+
+        let mut result = Ok(());
+        for mut listener in self.listeners.drain(..)
+        {
+            if let Err(err) = listener.stop()
+            {
+                result = Err(err);
+            }
+        }
+
+        // The listeners are gone, so this was the last way to wake the probe thread.
+        self.wake_prober = None;
+        if let Err(err) = join(self.probe_thread.take())
+        {
+            result = Err(err);
+        }
+
+        result
+    }
+
+    fn wake_prober(&self)
+    {
+        if let Some(wake_prober) = &self.wake_prober
+        {
+            let _ = wake_prober.send(());
+        }
+    }
+}
+
+impl Drop for ServiceResponder
+{
+    fn drop(&mut self)
+    {
+        if let Err(err) = self.shutdown()
+        {
+            debug!("Error while stopping threads: {}", err);
+        }
+    }
+}
+
+fn interface_addresses(interface: &NetworkInterface, ip_type: &IpType) -> Vec<IpAddr>
+{
+    let mut addresses = Vec::new();
+    if matches!(ip_type, IpType::V4 | IpType::DualStack)
+    {
+        addresses.extend(interface.ipv4.iter().map(|address| IpAddr::V4(*address)));
+    }
+
+    if matches!(ip_type, IpType::V6 | IpType::DualStack)
+    {
+        addresses.extend(interface.ipv6.iter().map(|address| IpAddr::V6(*address)));
+    }
+
+    addresses
+}
+
+// Send the probes and announcements that are due and sleep until the next one, or until a
+// registration is added or renamed.
+fn probe(links: Arc<Vec<Link>>, registry: Arc<Mutex<Registry>>, woken: mpsc::Receiver<()>) -> Result<(), DnsSdError>
+{
+    loop
+    {
+        let now = Instant::now();
+        let (due, wait) =
+        {
+            let mut registry = registry.lock().unwrap();
+            let due = registry.step(now);
+            (due, registry.time_to_next(now))
+        };
+
+        for registration in due.probes
+        {
+            debug!("Probing for {} on {}", registration.full_name(), registration.host);
+            for link in links.iter()
+            {
+                let sent = new_probe(&registration.unique_names(), &registration.probe_records(&link.addresses))
+                    .and_then(|probe| Ok(link.socket.send_to(&probe, link.multicast_addr)?));
+                if let Err(err) = sent
+                {
+                    debug!("Failed to send probe to {}: {}", link.multicast_addr, err);
+                }
+            }
+        }
+
+        for registration in due.announcements
+        {
+            debug!("Announcing {}", registration.full_name());
+            send_unsolicited(&links, |addresses| registration.announce_records(addresses));
+        }
+
+        let received = match wait
+        {
+            Some(wait) => woken.recv_timeout(wait),
+            None => woken.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected)
+        };
+
+        if let Err(mpsc::RecvTimeoutError::Disconnected) = received
+        {
+            debug!("Probe thread stopped");
+            return Ok(());
+        }
+    }
+}
+
+// Goodbyes are the records of the removed registrations with a TTL of 0, RFC 6762 section 10.1.
+// The service type record and the host addresses are left out while remaining registrations still
+// use them.
+fn send_goodbyes(links: &[Link], removed: &[ServiceRegistration], remaining: &[ServiceRegistration])
+{
+    send_unsolicited(links, |addresses|
+    {
+        let mut records = Vec::new();
+        for registration in removed
+        {
+            debug!("Saying goodbye for {}", registration.full_name());
+            records.extend([registration.ptr_record(), registration.srv_record(), registration.txt_record()]);
+
+            if !remaining.iter().any(|other| other.service().eq_ignore_ascii_case(&registration.service()))
+            {
+                records.push(registration.service_type_record());
+            }
+
+            if !remaining.iter().any(|other| other.host.eq_ignore_ascii_case(&registration.host))
+            {
+                records.extend(registration.address_records(addresses));
+            }
+        }
+
+        let mut records = dedup(records);
+        for record in records.iter_mut()
+        {
+            record.set_ttl(0);
+        }
+
+        records
+    });
+}
+
+// Multicast a response nobody asked for on every link, with the records for its addresses.
+fn send_unsolicited<F>(links: &[Link], records: F)
+    where F: Fn(&[IpAddr]) -> Vec<DnsSdResponse>
+{
+    for link in links
+    {
+        let sent = new_response(0, &[], &records(&link.addresses), &[])
+            .and_then(|response| Ok(link.socket.send_to(&response, link.multicast_addr)?));
+        if let Err(err) = sent
+        {
+            debug!("Failed to send response to {}: {}", link.multicast_addr, err);
+        }
+    }
+}
+
+fn respond(socket: UdpSocket, addresses: Vec<IpAddr>, registry: Arc<Mutex<Registry>>, wake_prober: mpsc::Sender<()>, stopped: Arc<AtomicBool>) -> Result<(), DnsSdError>
+{
+    let multicast_addr = multicast_addr_for(&socket)?;
+    let mut buffer: [u8; 9000] = [0u8; 9000];
+    let mut delayed: Vec<(Instant, Vec<u8>)> = Vec::new();
+
+    loop
+    {
+        // Wait no longer than until the next delayed response is due.
+        send_delayed(&socket, multicast_addr, &mut delayed);
+        let now = Instant::now();
+        let timeout = delayed.iter()
+            .map(|(due, _)| due.saturating_duration_since(now))
+            .min()
+            .unwrap_or(LISTEN_TIMEOUT)
+            .clamp(Duration::from_millis(1), LISTEN_TIMEOUT);
+        socket.set_read_timeout(Some(timeout))?;
+
+        let received = socket.recv_from(&mut buffer);
+        if stopped.load(Ordering::SeqCst)
+        {
+            debug!("Responder thread stopped");
+            return Ok(());
+        }
+
+        let (count, addr) = match received
+        {
+            Ok((count, addr)) => (count, addr),
+            Err(err) if matches!(err.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => continue,
+            Err(err) =>
+            {
+                debug!("Failed to receive data: {}", err);
+                continue;
+            }
+        };
+
+        let query = match DnsSdQuery::from(&buffer[..count], count)
+        {
+            Ok(query) => query,
+            Err(DnsSdError::NotDnsSdQuery) =>
+            {
+                // Responses from other hosts may use our names.
+                if let Ok(records) = DnsSdResponse::from(&buffer[..count], count)
+                {
+                    if registry.lock().unwrap().handle_response(&records, &addresses, Instant::now())
+                    {
+                        let _ = wake_prober.send(());
+                    }
+                }
+                continue;
+            },
+            Err(_) => continue
+        };
+
+        if !query.authorities.is_empty() && registry.lock().unwrap().handle_probe(&query.authorities, &addresses, Instant::now())
+        {
+            let _ = wake_prober.send(());
+        }
+
+        let (answers, additional) = answer(&registry.lock().unwrap().registered(), &query, &addresses);
+        if answers.is_empty()
+        {
+            continue;
+        }
+
+        if let Err(err) = send_response(&socket, &query, addr, multicast_addr, answers, additional, &mut delayed)
+        {
+            debug!("Failed to send response to {}: {}", addr, err);
+        }
+    }
+}
+
+// Queries sent from a port other than 5353 come from legacy resolvers and get a unicast response
+// with the question repeated. Queries asking for a unicast response get it, the rest is answered on
+// the multicast group. Delayed responses are queued with the time they are due.
+fn send_response(socket: &UdpSocket, query: &DnsSdQuery, addr: SocketAddr, multicast_addr: SocketAddr, answers: Vec<DnsSdResponse>, additional: Vec<DnsSdResponse>, delayed: &mut Vec<(Instant, Vec<u8>)>) -> Result<(), DnsSdError>
+{
+    if addr.port() != MULTICAST_PORT
+    {
+        let response = new_response(query.id, &query.questions, &legacy_records(answers), &legacy_records(additional))?;
+        socket.send_to(&response, addr)?;
+        return Ok(());
+    }
+
+    let response = new_response(0, &[], &answers, &additional)?;
+    if query.unicast_response
+    {
+        socket.send_to(&response, addr)?;
+        return Ok(());
+    }
+
+    // Shared records are delayed so the responses of several hosts don't collide, RFC 6762
+    // section 6.
+    if answers.iter().any(|answer| !answer.cache_flush())
+    {
+        delayed.push((Instant::now() + Duration::from_millis(fastrand::u64(20..=120)), response));
+        return Ok(());
+    }
+
+    socket.send_to(&response, multicast_addr)?;
+    Ok(())
+}
+
+// Send the delayed responses that are due and keep the others.
+fn send_delayed(socket: &UdpSocket, multicast_addr: SocketAddr, delayed: &mut Vec<(Instant, Vec<u8>)>)
+{
+    let now = Instant::now();
+    delayed.retain(|(due, response)|
+    {
+        if *due > now
+        {
+            return true;
+        }
+
+        if let Err(err) = socket.send_to(response, multicast_addr)
+        {
+            debug!("Failed to send delayed response to {}: {}", multicast_addr, err);
+        }
+        false
+    });
+}
+
+// Legacy resolvers don't know the cache flush bit and should not cache for long.
+fn legacy_records(records: Vec<DnsSdResponse>) -> Vec<DnsSdResponse>
+{
+    records.into_iter()
+        .map(|record| record.for_legacy_unicast(LEGACY_UNICAST_TTL))
+        .collect()
+}
+
+// Answers to the questions of a query and the additional records that go with them. Records the
+// querier listed as known answers with at least half of their TTL left are not sent again, RFC 6762
+// section 7.1.
+fn answer(registrations: &[ServiceRegistration], query: &DnsSdQuery, interface_addresses: &[IpAddr]) -> (Vec<DnsSdResponse>, Vec<DnsSdResponse>)
+{
+    let mut answers = Vec::new();
+    let mut additional = Vec::new();
+
+    for question in &query.questions
+    {
+        let asks_for = |qtype: Type| question.qtype == qtype || question.qtype == Type::ANY;
+
+        for registration in registrations
+        {
+            let address_records = registration.address_records(interface_addresses);
+
+            if question.name.eq_ignore_ascii_case(&registration.service()) && asks_for(Type::PTR)
+            {
+                answers.push(registration.ptr_record());
+                additional.push(registration.srv_record());
+                additional.push(registration.txt_record());
+                additional.extend(address_records.iter().cloned());
+            }
+
+            let service_type_record = registration.service_type_record();
+            if question.name.eq_ignore_ascii_case(service_type_record.label()) && asks_for(Type::PTR)
+            {
+                answers.push(service_type_record);
+            }
+
+            if question.name.eq_ignore_ascii_case(&registration.full_name())
+            {
+                if asks_for(Type::SRV)
+                {
+                    answers.push(registration.srv_record());
+                    additional.extend(address_records.iter().cloned());
+                }
+
+                if asks_for(Type::TXT)
+                {
+                    answers.push(registration.txt_record());
+                }
+            }
+
+            if question.name.eq_ignore_ascii_case(&registration.host)
+            {
+                answers.extend(address_records.into_iter()
+                    .filter(|record| question.qtype == Type::ANY || record.rtype() == question.qtype as u16));
+            }
+        }
+    }
+
+    answers.retain(|record| !is_known(record, &query.known_answers));
+    let answers = dedup(answers);
+
+    additional.retain(|record| !is_known(record, &query.known_answers) && !answers.iter().any(|answer| answer.is_same_record(record)));
+    let additional = dedup(additional);
+
+    (answers, additional)
+}
+
+fn is_known(record: &DnsSdResponse, known_answers: &[DnsSdResponse]) -> bool
+{
+    known_answers.iter().any(|known| known.is_same_record(record) && known.ttl() >= record.ttl() / 2)
+}
+
+fn join(thread: Option<thread::JoinHandle<Result<(), DnsSdError>>>) -> Result<(), DnsSdError>
+{
+    match thread
+    {
+        Some(thread) => thread.join().unwrap_or(Err(DnsSdError::ThreadPanicked)),
+        None => Ok(())
+    }
+}
+
+fn dedup(records: Vec<DnsSdResponse>) -> Vec<DnsSdResponse>
+{
+    let mut kept: Vec<DnsSdResponse> = Vec::new();
+    for record in records
+    {
+        if !kept.iter().any(|other| other.is_same_record(&record))
+        {
+            kept.push(record);
+        }
+    }
+
+    kept
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::time::Instant;
+    use crate::dns::{ Message, Question };
+    use crate::{ DiscoveryMode, ServiceDiscovery, ServiceEvent };
+
+    fn registration() -> ServiceRegistration
+    {
+        let mut registration = ServiceRegistration::new("Living Room", "_http._tcp", "firmware.local", 80);
+        registration.txt_records = vec![String::from("path=/")];
+        registration
+    }
+
+    fn query(questions: &[(&str, Type)], known_answers: Vec<DnsSdResponse>) -> DnsSdQuery
+    {
+        let mut message = Message::query();
+        for (name, qtype) in questions
+        {
+            message.add_question(Question { name: String::from(*name), qtype: *qtype }, false);
+        }
+
+        for record in known_answers
+        {
+            message.add_answer(record);
+        }
+
+        let packet = message.to_bytes().unwrap();
+        DnsSdQuery::from(&packet, packet.len()).unwrap()
+    }
+
+    fn rtypes(records: &[DnsSdResponse]) -> Vec<u16>
+    {
+        records.iter().map(|record| record.rtype()).collect()
+    }
+
+    #[test]
+    fn test_answer()
+    {
+        let registrations = vec![registration()];
+        let addresses = vec![IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20))];
+
+        let (answers, additional) = answer(&registrations, &query(&[("_HTTP._tcp.local", Type::PTR)], Vec::new()), &addresses);
+        assert_eq!(rtypes(&answers), vec![Type::PTR as u16]);
+        assert_eq!(rtypes(&additional), vec![Type::SRV as u16, Type::TXT as u16, Type::A as u16]);
+
+        let (answers, additional) = answer(&registrations, &query(&[("Living Room._http._tcp.local", Type::SRV), ("Living Room._http._tcp.local", Type::TXT), ("firmware.local", Type::A)], Vec::new()), &addresses);
+        assert_eq!(rtypes(&answers), vec![Type::SRV as u16, Type::TXT as u16, Type::A as u16]);
+        assert!(additional.is_empty());
+
+        let (answers, _) = answer(&registrations, &query(&[("firmware.local", Type::AAAA), ("_ipp._tcp.local", Type::PTR)], Vec::new()), &addresses);
+        assert!(answers.is_empty());
+
+        let (answers, _) = answer(&registrations, &query(&[("_services._dns-sd._udp.local", Type::PTR)], Vec::new()), &addresses);
+        assert_eq!(answers.len(), 1);
+        assert!(answers[0].is_same_record(&registration().service_type_record()));
+
+        // Fixed addresses are used instead of the addresses of the interface.
+        let mut fixed = registration();
+        fixed.addresses = vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))];
+        let (answers, _) = answer(&[fixed.clone()], &query(&[("firmware.local", Type::ANY)], Vec::new()), &addresses);
+        assert_eq!(answers.len(), 1);
+        assert!(answers[0].is_same_record(&fixed.address_records(&[])[0]));
+    }
+
+    #[test]
+    fn test_known_answer_suppression()
+    {
+        let registrations = vec![registration()];
+        let mut known = registration().ptr_record();
+
+        known.set_ttl(4500 / 2);
+        let (answers, _) = answer(&registrations, &query(&[("_http._tcp.local", Type::PTR)], vec![known.clone()]), &[]);
+        assert!(answers.is_empty());
+
+        known.set_ttl(4500 / 2 - 1);
+        let (answers, _) = answer(&registrations, &query(&[("_http._tcp.local", Type::PTR)], vec![known]), &[]);
+        assert_eq!(answers.len(), 1);
+    }
+
+    #[test]
+    fn test_delayed_response()
+    {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let group = UdpSocket::bind("127.0.0.1:0").unwrap();
+        group.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let multicast_addr = group.local_addr().unwrap();
+        let querier = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), MULTICAST_PORT);
+
+        // The shared PTR record is queued instead of sent.
+        let start = Instant::now();
+        let mut delayed = Vec::new();
+        send_response(&socket, &query(&[("_http._tcp.local", Type::PTR)], Vec::new()), querier, multicast_addr, vec![registration().ptr_record()], Vec::new(), &mut delayed).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(20));
+        assert_eq!(delayed.len(), 1);
+        assert!(delayed[0].0 >= start + Duration::from_millis(20) && delayed[0].0 <= Instant::now() + Duration::from_millis(120));
+
+        send_delayed(&socket, multicast_addr, &mut delayed);
+        assert_eq!(delayed.len(), 1);
+
+        thread::sleep(Duration::from_millis(120));
+        send_delayed(&socket, multicast_addr, &mut delayed);
+        assert!(delayed.is_empty());
+
+        let mut buffer = [0u8; 1500];
+        let (count, _) = group.recv_from(&mut buffer).unwrap();
+        let records = DnsSdResponse::from(&buffer, count).unwrap();
+        assert!(records[0].is_same_record(&registration().ptr_record()));
+    }
+
+    #[test]
+    fn test_register()
+    {
+        let mut responder = ServiceResponder::new(IpType::V4).unwrap();
+
+        assert_eq!(responder.register(ServiceRegistration::new("", "_http._tcp", "firmware.local", 80)), Err(DnsSdError::LabelInvalid));
+
+        let mut registration = ServiceRegistration::new("Register Test", "_libdnssd-register._tcp", "libdnssd-register.local", 80);
+        assert_eq!(responder.register(registration.clone()), Ok(registration.clone()));
+        registration.port = 8080;
+        assert_eq!(responder.register(registration.clone()), Ok(registration.clone()));
+        assert_eq!(responder.get_registrations(), vec![registration]);
+
+        responder.unregister("register test._libdnssd-register._tcp.local");
+        assert!(responder.get_registrations().is_empty());
+        assert_eq!(responder.shutdown(), Ok(()));
+    }
+
+    #[test]
+    fn test_discovered_by_service_discovery()
+    {
+        let mut responder = ServiceResponder::new(IpType::V4).unwrap();
+        let mut registration = ServiceRegistration::new("Responder Test", "_libdnssd-responder._tcp", "libdnssd-responder.local", 8080);
+        registration.txt_records = vec![String::from("path=/")];
+        assert_eq!(responder.register(registration.clone()), Ok(registration));
+
+        let discovery = ServiceDiscovery::new(IpType::V4).unwrap();
+        let start = Instant::now();
+        let instance = discovery.resolve("_libdnssd-responder._tcp.local", Duration::from_secs(5)).unwrap();
+
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(instance.name, "Responder Test");
+        assert_eq!(instance.host, "libdnssd-responder.local");
+        assert_eq!(instance.port, 8080);
+        assert_eq!(instance.txt_records, vec![String::from("path=/")]);
+        assert!(!instance.addresses.is_empty());
+    }
+
+    #[test]
+    fn test_announce_and_goodbye()
+    {
+        // Passive discovery only learns about the instance from the announcement and the goodbye.
+        let mut discovery = ServiceDiscovery::new(IpType::V4).unwrap();
+        discovery.set_mode(DiscoveryMode::Passive).unwrap();
+        discovery.find_service("_libdnssd-goodbye._tcp.local");
+        let events = discovery.subscribe();
+
+        let mut responder = ServiceResponder::new(IpType::V4).unwrap();
+        let registration = ServiceRegistration::new("Goodbye Test", "_libdnssd-goodbye._tcp", "libdnssd-goodbye.local", 80);
+        assert_eq!(responder.register(registration.clone()), Ok(registration.clone()));
+
+        let wait_for = |matches: &dyn Fn(&ServiceEvent) -> bool|
+        {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while let Ok(event) = events.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            {
+                if matches(&event)
+                {
+                    return true;
+                }
+            }
+
+            false
+        };
+
+        assert!(wait_for(&|event| matches!(event, ServiceEvent::Resolved(instance) if instance.name == "Goodbye Test")));
+
+        responder.unregister(&registration.full_name());
+        assert!(wait_for(&|event| *event == ServiceEvent::Removed
+        {
+            service: String::from("_libdnssd-goodbye._tcp.local"),
+            instance: registration.full_name()
+        }));
+    }
+
+    #[test]
+    fn test_name_conflict()
+    {
+        let mut first = ServiceResponder::new(IpType::V4).unwrap();
+        let mut second = ServiceResponder::new(IpType::V4).unwrap();
+
+        let registration = ServiceRegistration::new("Conflict Test", "_libdnssd-conflict._tcp", "libdnssd-conflict.local", 80);
+        assert_eq!(first.register(registration.clone()), Ok(registration.clone()));
+
+        // Same instance name on the same host, but with another port.
+        let mut other = registration.clone();
+        other.port = 81;
+        let renamed = second.register(other).unwrap();
+
+        assert_eq!(renamed.full_name(), "Conflict Test (2)._libdnssd-conflict._tcp.local");
+        assert_eq!(renamed.host, "libdnssd-conflict.local");
+        assert_eq!(second.get_registrations(), vec![renamed]);
+        assert_eq!(first.get_registrations(), vec![registration]);
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+use std::time::{ Duration, Instant };
+use log::debug;
+
+// Source of the current time, replaced in tests to step through a schedule.
+pub trait Clock: Send
+{
+    fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock
+{
+    fn now(&self) -> Instant
+    {
+        Instant::now()
+    }
+}
+
+// When queries for a service are sent, RFC 6762 section 5.2.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QuerySchedule
+{
+    // Time between the first and the second query, doubled after every query.
+    pub initial_interval: Duration,
+    pub max_interval: Duration,
+    // Random delay added to every query so hosts that start at the same time do not query at once.
+    pub jitter_min: Duration,
+    pub jitter_max: Duration,
+    // Percentages of the TTL at which a cached record is queried for again before it expires.
+    pub refresh_points: Vec<u32>
+}
+
+impl Default for QuerySchedule
+{
+    fn default() -> QuerySchedule
+    {
+        QuerySchedule
+        {
+            initial_interval: Duration::from_secs(1),
+            max_interval: Duration::from_secs(60 * 60),
+            jitter_min: Duration::from_millis(20),
+            jitter_max: Duration::from_millis(120),
+            refresh_points: vec![80, 85, 90, 95]
+        }
+    }
+}
+
+struct ServiceState
+{
+    next: Instant,
+    interval: Duration,
+    last_sent: Option<Instant>
+}
+
+pub struct QueryScheduler
+{
+    schedule: QuerySchedule,
+    clock: Box<dyn Clock>,
+    rng: fastrand::Rng,
+    services: HashMap<String, ServiceState>
+}
+
+impl QueryScheduler
+{
+    pub fn new(schedule: QuerySchedule, clock: Box<dyn Clock>) -> QueryScheduler
+    {
+        QueryScheduler
+        {
+            schedule,
+            clock,
+            rng: fastrand::Rng::new(),
+            services: HashMap::new()
+        }
+    }
+
+    pub fn set_schedule(&mut self, schedule: QuerySchedule)
+    {
+        self.schedule = schedule;
+        self.restart();
+    }
+
+    // Start over from the initial interval, e.g. after the network changed.
+    pub fn restart(&mut self)
+    {
+        self.services.clear();
+    }
+
+    // Services to query now. A service is due when its backoff interval has passed or one of its
+    // cached records, given as received time and TTL, reached a refresh point since the last query.
+    // Each comes with whether to ask for unicast responses, only on the first query after a start
+    // or restart (RFC 6762 section 5.4).
+    pub fn due<F>(&mut self, services: &[String], records: F) -> Vec<(String, bool)>
+        where F: Fn(&str) -> Vec<(Instant, Duration)>
+    {
+        let now = self.clock.now();
+        self.services.retain(|service, _| services.contains(service));
+
+        let mut due = Vec::new();
+        for service in services
+        {
+            if !self.services.contains_key(service)
+            {
+                let next = now + self.jitter();
+                self.services.insert(service.clone(), ServiceState { next, interval: self.schedule.initial_interval, last_sent: None });
+            }
+
+            let jitter = self.jitter();
+            let state = self.services.get_mut(service).unwrap();
+
+            let backoff_due = now >= state.next;
+            let refresh_due = match state.last_sent
+            {
+                Some(last_sent) => next_refresh(&records(service), &self.schedule.refresh_points, last_sent).is_some_and(|refresh| refresh <= now),
+                None => false
+            };
+
+            if !backoff_due && !refresh_due
+            {
+                continue;
+            }
+
+            if backoff_due
+            {
+                state.next = now + state.interval + jitter;
+                state.interval = (state.interval * 2).min(self.schedule.max_interval);
+            }
+            else
+            {
+                debug!("Refreshing records of service: {}", service);
+            }
+
+            let unicast_response = state.last_sent.is_none();
+            state.last_sent = Some(now);
+            due.push((service.clone(), unicast_response));
+        }
+
+        due
+    }
+
+    // Time until the next backoff query. Refresh points are not included, they depend on records
+    // that may arrive in the meantime.
+    pub fn time_to_next(&self) -> Option<Duration>
+    {
+        let now = self.clock.now();
+        self.services.values()
+            .map(|state| state.next.saturating_duration_since(now))
+            .min()
+    }
+
+    fn jitter(&mut self) -> Duration
+    {
+        let min = self.schedule.jitter_min.as_millis() as u64;
+        let max = (self.schedule.jitter_max.as_millis() as u64).max(min);
+
+        Duration::from_millis(self.rng.u64(min..=max))
+    }
+}
+
+// First refresh point of any record after the given time.
+fn next_refresh(records: &[(Instant, Duration)], refresh_points: &[u32], after: Instant) -> Option<Instant>
+{
+    records.iter()
+        .flat_map(|(received, ttl)| refresh_points.iter().map(move |percent| *received + *ttl * *percent / 100))
+        .filter(|refresh| *refresh > after)
+        .min()
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::sync::{ Arc, Mutex };
+
+    #[derive(Clone)]
+    struct ManualClock
+    {
+        now: Arc<Mutex<Instant>>
+    }
+
+    impl ManualClock
+    {
+        fn advance(&self, duration: Duration)
+        {
+            *self.now.lock().unwrap() += duration;
+        }
+    }
+
+    impl Clock for ManualClock
+    {
+        fn now(&self) -> Instant
+        {
+            *self.now.lock().unwrap()
+        }
+    }
+
+    fn scheduler(schedule: QuerySchedule) -> (QueryScheduler, ManualClock)
+    {
+        let clock = ManualClock { now: Arc::new(Mutex::new(Instant::now())) };
+        (QueryScheduler::new(schedule, Box::new(clock.clone())), clock)
+    }
+
+    // Advance in steps of a millisecond and return the time passed until the service was due.
+    fn time_to_due(scheduler: &mut QueryScheduler, clock: &ManualClock, services: &[String]) -> Duration
+    {
+        let mut waited = Duration::ZERO;
+        while scheduler.due(services, |_| Vec::new()).is_empty()
+        {
+            clock.advance(Duration::from_millis(1));
+            waited += Duration::from_millis(1);
+        }
+
+        waited
+    }
+
+    #[test]
+    fn test_backoff()
+    {
+        let schedule = QuerySchedule
+        {
+            max_interval: Duration::from_secs(8),
+            ..QuerySchedule::default()
+        };
+        let (mut scheduler, clock) = scheduler(schedule);
+        let services = vec![String::from("_hap._tcp.local")];
+
+        // The first query waits for the jitter only.
+        let waited = time_to_due(&mut scheduler, &clock, &services);
+        assert!(waited >= Duration::from_millis(20) && waited <= Duration::from_millis(120));
+
+        for interval in [1, 2, 4, 8, 8]
+        {
+            let waited = time_to_due(&mut scheduler, &clock, &services);
+            assert!(waited >= Duration::from_secs(interval) + Duration::from_millis(20));
+            assert!(waited <= Duration::from_secs(interval) + Duration::from_millis(120));
+        }
+
+        // Restarting goes back to the first query.
+        scheduler.restart();
+        assert!(time_to_due(&mut scheduler, &clock, &services) <= Duration::from_millis(120));
+        assert!(scheduler.time_to_next().unwrap() >= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_unicast_response_on_first_query()
+    {
+        let (mut scheduler, clock) = scheduler(QuerySchedule::default());
+        let services = vec![String::from("_hap._tcp.local")];
+
+        assert!(scheduler.due(&services, |_| Vec::new()).is_empty());
+        clock.advance(Duration::from_millis(120));
+        assert_eq!(scheduler.due(&services, |_| Vec::new()), vec![(services[0].clone(), true)]);
+        clock.advance(Duration::from_millis(1120));
+        assert_eq!(scheduler.due(&services, |_| Vec::new()), vec![(services[0].clone(), false)]);
+
+        scheduler.restart();
+        assert!(scheduler.due(&services, |_| Vec::new()).is_empty());
+        clock.advance(Duration::from_millis(120));
+        assert_eq!(scheduler.due(&services, |_| Vec::new()), vec![(services[0].clone(), true)]);
+    }
+
+    #[test]
+    fn test_refresh_points()
+    {
+        let schedule = QuerySchedule
+        {
+            initial_interval: Duration::from_secs(3600),
+            ..QuerySchedule::default()
+        };
+        let (mut scheduler, clock) = scheduler(schedule);
+        let services = vec![String::from("_hap._tcp.local")];
+        let received = clock.now();
+        let records = |_: &str| vec![(received, Duration::from_secs(100))];
+
+        assert!(scheduler.due(&services, records).is_empty());
+        clock.advance(Duration::from_millis(120));
+        assert_eq!(scheduler.due(&services, records), vec![(services[0].clone(), true)]);
+
+        let mut sent = Vec::new();
+        for _ in 0..100
+        {
+            clock.advance(Duration::from_secs(1));
+            if !scheduler.due(&services, records).is_empty()
+            {
+                sent.push(clock.now().duration_since(received).as_secs());
+            }
+        }
+
+        assert_eq!(sent, vec![80, 85, 90, 95]);
+    }
+
+    #[test]
+    fn test_removed_service_is_forgotten()
+    {
+        let (mut scheduler, clock) = scheduler(QuerySchedule::default());
+        let services = vec![String::from("_hap._tcp.local")];
+
+        assert!(scheduler.due(&services, |_| Vec::new()).is_empty());
+        clock.advance(Duration::from_millis(120));
+        assert_eq!(scheduler.due(&services, |_| Vec::new()), vec![(services[0].clone(), true)]);
+        assert!(scheduler.due(&[], |_| Vec::new()).is_empty());
+        assert!(scheduler.time_to_next().is_none());
+    }
+}
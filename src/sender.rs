@@ -1,129 +1,820 @@
-use std::sync::{ Arc, Mutex };
+use std::net::{ Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket };
+use std::sync::{ Arc, Mutex, mpsc, atomic::{ AtomicBool, Ordering } };
 use std::thread;
+use std::time::{ Duration, Instant };
 use log::debug;
 
 use crate::dnssd_error::DnsSdError;
-use crate::socket::{ create_sender_socket, MULTICAST_ADDR_IPV6, MULTICAST_ADDR_IPV4, MULTICAST_PORT };
-use crate::dns::{ new_query, DnsSdResponse };
+use crate::socket::{ create_multicast_sockets, create_sender_sockets, multicast_addr_for };
+use crate::dns::{ new_queries, DnsSdResponse };
 use crate::discovery_handler::DiscoveryHandler;
-use crate::IpType;
+use crate::service_event::EventDispatcher;
+use crate::interface::{ diff_interfaces, InterfaceFilter, NetworkInterface };
+use crate::scheduler::{ QueryScheduler, QuerySchedule, SystemClock };
+use crate::{ IpType, DiscoveryMode };
 
 pub struct Sender
 {
-    _send_thread: Option<thread::JoinHandle<Result<(), DnsSdError>>>,
-    _listen_thread: Option<thread::JoinHandle<Result<(), DnsSdError>>>
+    send_thread: Option<thread::JoinHandle<Result<(), DnsSdError>>>,
+    monitor_thread: Option<thread::JoinHandle<Result<(), DnsSdError>>>,
+    // Wakes up the send thread, dropping the channel stops it.
+    send_commands: Option<mpsc::Sender<SendCommand>>,
+    // Dropping the channel stops the monitor thread.
+    stop_monitor: Option<mpsc::Sender<()>>,
+    network: Arc<Mutex<Network>>,
+    scheduler: Arc<Mutex<QueryScheduler>>,
+    ip_type: IpType,
+    handler: Arc<Mutex<DiscoveryHandler>>,
+    dispatcher: Arc<Mutex<EventDispatcher>>
+}
+
+// How often the listen thread wakes up without traffic to expire records and report removals.
+const LISTEN_TIMEOUT: Duration = Duration::from_secs(1);
+
+// How often interfaces are checked for changes.
+const INTERFACE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// Longest time the send thread sleeps, so refresh points of newly cached records are not missed.
+const MAX_SEND_WAIT: Duration = Duration::from_secs(1);
+
+enum SendCommand
+{
+    // Look at the schedule again, e.g. after a service was added.
+    Schedule,
+    // Start querying from the initial interval, e.g. after an interface came up.
+    Restart
+}
+
+// The interfaces in use and the links open on them, shared with the send and monitor threads.
+struct Network
+{
+    mode: DiscoveryMode,
+    interfaces: Vec<NetworkInterface>,
+    links: Vec<Link>
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LinkKind
+{
+    // Ephemeral port that queries are sent from, responses to them come back here.
+    Query,
+    // Joined to the multicast group on port 5353, receives all mDNS traffic on the interface.
+    Multicast
+}
+
+// A socket on one interface and IP version, with the thread listening on it.
+struct Link
+{
+    interface: u32,
+    kind: LinkKind,
+    socket: UdpSocket,
+    multicast_addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    listen_thread: Option<thread::JoinHandle<Result<(), DnsSdError>>>
+}
+
+impl Link
+{
+    fn open(interface: u32, kind: LinkKind, socket: UdpSocket, handler: Arc<Mutex<DiscoveryHandler>>, dispatcher: Arc<Mutex<EventDispatcher>>) -> Result<Link, DnsSdError>
+    {
+        socket.set_read_timeout(Some(LISTEN_TIMEOUT))?;
+        let multicast_addr = multicast_addr_for(&socket)?;
+        let listen_socket = socket.try_clone()?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let listen_thread = thread::spawn(move || listen(listen_socket, interface, handler, dispatcher, stopped));
+
+        Ok(Link
+        {
+            interface,
+            kind,
+            socket,
+            multicast_addr,
+            stop,
+            listen_thread: Some(listen_thread)
+        })
+    }
+
+    fn close(&mut self) -> Result<(), DnsSdError>
+    {
+        self.stop.store(true, Ordering::SeqCst);
+        wake_listener(&self.socket);
+
+        join(self.listen_thread.take())
+    }
 }
 
 impl Sender
 {
-    pub fn new(handler: Arc<Mutex<DiscoveryHandler>>, ip_type: &IpType) -> Result<Sender, DnsSdError>
+    pub fn new(handler: Arc<Mutex<DiscoveryHandler>>, dispatcher: Arc<Mutex<EventDispatcher>>, ip_type: &IpType, filter: InterfaceFilter, interfaces: Vec<NetworkInterface>) -> Result<Sender, DnsSdError>
     {
-        let listen_handler = handler.clone();
-        let listen_socket = match create_sender_socket(ip_type)
+        let mut links: Vec<Link> = Vec::new();
+        for interface in interfaces.iter()
         {
-            Ok(socket) => socket,
-            Err(err) =>
+            match open_interface(ip_type, DiscoveryMode::Active, interface, &handler, &dispatcher)
             {
-                debug!("Failed to create sender socket: {}", err);
-                return Err(err);
+                Ok(opened) => links.extend(opened),
+                Err(err) =>
+                {
+                    debug!("Failed to create sender socket: {}", err);
+                    close_links(links);
+                    return Err(err);
+                }
             }
-        };
+        }
 
-        let send_socket = listen_socket.try_clone()?;
-        let send_handler = listen_handler.clone();
+        if links.is_empty()
+        {
+            return Err(DnsSdError::InterfaceNotFound);
+        }
 
-        let listen_thread = thread::spawn(move ||
+        let network = Arc::new(Mutex::new(Network
         {
-            let mut buffer: [u8; 4096] = [0u8; 4096];
+            mode: DiscoveryMode::Active,
+            interfaces,
+            links
+        }));
+        let scheduler = Arc::new(Mutex::new(QueryScheduler::new(QuerySchedule::default(), Box::new(SystemClock))));
+        let (send_commands, send_requested) = mpsc::channel::<SendCommand>();
+        let (stop_monitor, monitor_stopped) = mpsc::channel::<()>();
 
+        let send_handler = handler.clone();
+        let send_network = network.clone();
+        let send_scheduler = scheduler.clone();
+        let send_thread = thread::spawn(move ||
+        {
             loop
             {
-                let (count, addr) = match listen_socket.recv_from(&mut buffer)
+                let due =
                 {
-                    Ok((count, addr)) => (count, addr),
-                    Err(err) =>
-                    {
-                        debug!("Failed to receive data: {}", err);
-                        continue;
-                    }
+                    let handler = send_handler.lock().unwrap();
+                    send_scheduler.lock().unwrap().due(handler.get_services(), |service| handler.refresh_records(service))
                 };
 
-                debug!("Received {} bytes from {}", count, addr);
-
-                // Only parse buffer if we are looking for services.
-                if listen_handler.lock().unwrap().get_services().len() == 0
+                // A failed query is retried on the schedule, the interface may only be down until
+                // the monitor notices.
+                for (service, unicast_response) in due
                 {
-                    continue;
+                    debug!("Sending query for service: {}", service);
+                    let sent = send_query(&send_network.lock().unwrap().links, |interface|
+                    {
+                        let handler = send_handler.lock().unwrap();
+                        let now = Instant::now();
+                        new_queries(&handler.questions(&service, interface, now), &handler.known_answers(&service, interface, now), unicast_response)
+                    });
+
+                    if let Err(err) = sent
+                    {
+                        debug!("Failed to send query for service {}: {}", service, err);
+                    }
                 }
 
-                let (service_label, responses) = match DnsSdResponse::from(&buffer, count)
+                // Wait for the next query, a command or to be stopped.
+                let wait = send_scheduler.lock().unwrap().time_to_next().unwrap_or(MAX_SEND_WAIT).min(MAX_SEND_WAIT);
+                match send_requested.recv_timeout(wait)
                 {
-                    Ok(responses) => responses,
-                    Err(err) =>
+                    Ok(SendCommand::Restart) => send_scheduler.lock().unwrap().restart(),
+                    Ok(SendCommand::Schedule) | Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                    Err(mpsc::RecvTimeoutError::Disconnected) =>
                     {
-                        debug!("Failed to parse response: {}", err);
-                        continue;
+                        debug!("Send thread stopped");
+                        return Ok(());
                     }
-                };
-
-                debug!("Parsed response:\n{:?}", responses);
+                }
+            }
+        });
 
-                if handler.lock().unwrap().get_services().contains(&service_label)
+        let monitor = Monitor
+        {
+            ip_type: ip_type.clone(),
+            filter,
+            network: network.clone(),
+            handler: handler.clone(),
+            dispatcher: dispatcher.clone(),
+            send_commands: send_commands.clone()
+        };
+        let monitor_thread = thread::spawn(move ||
+        {
+            let mut monitor = monitor;
+            loop
+            {
+                match monitor_stopped.recv_timeout(INTERFACE_POLL_INTERVAL)
                 {
-                    handler.lock().unwrap().add_response(service_label, responses);
+                    Err(mpsc::RecvTimeoutError::Timeout) => monitor.check(),
+                    _ =>
+                    {
+                        debug!("Monitor thread stopped");
+                        return Ok(());
+                    }
                 }
             }
         });
 
-        let new_ip_type = ip_type.clone();
-        let send_thread = thread::spawn(move ||
+        Ok(Sender
         {
-            loop
+            send_thread: Some(send_thread),
+            monitor_thread: Some(monitor_thread),
+            send_commands: Some(send_commands),
+            stop_monitor: Some(stop_monitor),
+            network,
+            scheduler,
+            ip_type: ip_type.clone(),
+            handler,
+            dispatcher
+        })
+    }
+
+    // Reopen the links on every interface for the new mode. On error the old links are kept.
+    pub fn set_mode(&mut self, mode: DiscoveryMode) -> Result<(), DnsSdError>
+    {
+        let old_links =
+        {
+            let mut network = self.network.lock().unwrap();
+            if network.mode == mode
             {
-                // For each service in handler, send a query.
-                for service in send_handler.lock().unwrap().get_services()
+                return Ok(());
+            }
+
+            let mut links: Vec<Link> = Vec::new();
+            for interface in network.interfaces.clone().iter()
+            {
+                match open_interface(&self.ip_type, mode, interface, &self.handler, &self.dispatcher)
                 {
-                    debug!("Sending query for service: {}", service);
-                    let query = new_query(service)?;
+                    Ok(opened) => links.extend(opened),
+                    Err(err) =>
+                    {
+                        debug!("Failed to open interface {}: {}", interface.name, err);
+                        drop(network);
+                        close_links(links);
+                        return Err(err);
+                    }
+                }
+            }
+
+            network.mode = mode;
+            std::mem::replace(&mut network.links, links)
+        };
+
+        close_links(old_links);
+        self.send_command(SendCommand::Restart);
+
+        Ok(())
+    }
+
+    pub fn set_query_schedule(&mut self, schedule: QuerySchedule)
+    {
+        self.scheduler.lock().unwrap().set_schedule(schedule);
+        self.send_command(SendCommand::Schedule);
+    }
+
+    // Let the send thread pick up services added since it last looked.
+    pub fn schedule_queries(&self)
+    {
+        self.send_command(SendCommand::Schedule);
+    }
+
+    fn send_command(&self, command: SendCommand)
+    {
+        if let Some(send_commands) = &self.send_commands
+        {
+            let _ = send_commands.send(command);
+        }
+    }
+
+    // Stop all threads and wait for them to finish. The first error returned by a thread is
+    // reported. Calling it again does nothing.
+    pub fn shutdown(&mut self) -> Result<(), DnsSdError>
+    {
+        // The monitor goes first so it does not open new links while the others are closed.
+        self.stop_monitor.take();
+        let mut result = join(self.monitor_thread.take());
+
+        self.send_commands.take();
+        result = result.and(join(self.send_thread.take()));
+
+        let links: Vec<Link> = self.network.lock().unwrap().links.drain(..).collect();
+        for mut link in links
+        {
+            result = result.and(link.close());
+        }
+
+        result
+    }
+}
+
+impl Drop for Sender
+{
+    fn drop(&mut self)
+    {
+        if let Err(err) = self.shutdown()
+        {
+            debug!("Error while stopping threads: {}", err);
+        }
+    }
+}
+
+// Keeps the links in line with the interfaces matching the filter.
+struct Monitor
+{
+    ip_type: IpType,
+    filter: InterfaceFilter,
+    network: Arc<Mutex<Network>>,
+    handler: Arc<Mutex<DiscoveryHandler>>,
+    dispatcher: Arc<Mutex<EventDispatcher>>,
+    send_commands: mpsc::Sender<SendCommand>
+}
+
+impl Monitor
+{
+    // Close the links of interfaces that went away or changed address and forget what was heard on
+    // them, then open links on new interfaces and restart querying.
+    fn check(&mut self)
+    {
+        let current = self.filter.current();
+        let (gone, added, readdressed) =
+        {
+            let network = self.network.lock().unwrap();
+            diff_interfaces(&network.interfaces, &current)
+        };
+
+        if gone.is_empty() && added.is_empty() && readdressed.is_empty()
+        {
+            return;
+        }
+
+        for interface in gone
+        {
+            debug!("Interface gone: {}", interface.name);
+            self.close_interface(&interface);
+        }
+
+        // Still the same link, so the records learned on it stay. Only the sockets are bound to
+        // the old addresses and are opened again.
+        for interface in readdressed
+        {
+            debug!("Interface changed address: {}", interface.name);
+            let closing =
+            {
+                let mut network = self.network.lock().unwrap();
+                let (closing, kept) = network.links.drain(..).partition(|link| link.interface == interface.index);
+                network.links = kept;
+                closing
+            };
+            close_links(closing);
 
-                    match new_ip_type
+            let mut network = self.network.lock().unwrap();
+            match open_interface(&self.ip_type, network.mode, &interface, &self.handler, &self.dispatcher)
+            {
+                Ok(links) =>
+                {
+                    network.links.extend(links);
+                    for active in network.interfaces.iter_mut().filter(|active| active.index == interface.index)
                     {
-                        IpType::V4 =>
-                        {
-                            match send_socket.send_to(&query, (MULTICAST_ADDR_IPV4, MULTICAST_PORT))
-                            {
-                                Ok(_) => {},
-                                Err(err) =>
-                                {
-                                    debug!("Failed to send query: {}", err);
-                                    return Err(err.into());
-                                }
-                            }
-                        },
-                        IpType::V6 =>
-                        {
-                            match send_socket.send_to(&query, (MULTICAST_ADDR_IPV6, MULTICAST_PORT))
-                            {
-                                Ok(_) => {},
-                                Err(err) =>
-                                {
-                                    debug!("Failed to send query: {}", err);
-                                    return Err(err.into());
-                                }
-                            }
-                        }
+                        *active = interface.clone();
                     }
+                },
+                Err(err) =>
+                {
+                    // Unusable now, so handled as gone and retried as added on the next check.
+                    debug!("Failed to open interface {}: {}", interface.name, err);
+                    drop(network);
+                    self.close_interface(&interface);
                 }
+            }
+        }
+
+        for interface in added
+        {
+            debug!("Interface added: {}", interface.name);
+
+            // An interface that can not be used yet, e.g. without an address, is retried on the
+            // next check.
+            let mut network = self.network.lock().unwrap();
+            match open_interface(&self.ip_type, network.mode, &interface, &self.handler, &self.dispatcher)
+            {
+                Ok(links) =>
+                {
+                    network.links.extend(links);
+                    network.interfaces.push(interface);
+                },
+                Err(err) => debug!("Failed to open interface {}: {}", interface.name, err)
+            }
+        }
+
+        let events =
+        {
+            let mut handler = self.handler.lock().unwrap();
+            handler.collect_events()
+        };
+        self.dispatcher.lock().unwrap().dispatch(events);
+
+        let _ = self.send_commands.send(SendCommand::Restart);
+    }
+
+    // Stop using an interface and forget the records learned on it.
+    fn close_interface(&self, interface: &NetworkInterface)
+    {
+        let closing =
+        {
+            let mut network = self.network.lock().unwrap();
+            network.interfaces.retain(|active| active.index != interface.index);
+
+            let (closing, kept) = network.links.drain(..).partition(|link| link.interface == interface.index);
+            network.links = kept;
+            closing
+        };
+
+        // Closed before the records are dropped, so the listen threads do not add new ones.
+        close_links(closing);
+        self.handler.lock().unwrap().remove_interface(interface.index);
+    }
+}
+
+// Open the links the mode needs on an interface, all of them or none.
+fn open_interface(ip_type: &IpType, mode: DiscoveryMode, interface: &NetworkInterface, handler: &Arc<Mutex<DiscoveryHandler>>, dispatcher: &Arc<Mutex<EventDispatcher>>) -> Result<Vec<Link>, DnsSdError>
+{
+    let mut sockets: Vec<(LinkKind, UdpSocket)> = Vec::new();
+    if mode != DiscoveryMode::Passive
+    {
+        for (_, socket) in create_sender_sockets(ip_type, std::slice::from_ref(interface))?
+        {
+            sockets.push((LinkKind::Query, socket));
+        }
+    }
+
+    if mode != DiscoveryMode::Active
+    {
+        for socket in create_multicast_sockets(ip_type, interface)?
+        {
+            sockets.push((LinkKind::Multicast, socket));
+        }
+    }
+
+    open_links(interface.index, sockets, handler, dispatcher)
+}
+
+// Open every link, or none of them, so an error does not leave threads behind.
+fn open_links(interface: u32, sockets: Vec<(LinkKind, UdpSocket)>, handler: &Arc<Mutex<DiscoveryHandler>>, dispatcher: &Arc<Mutex<EventDispatcher>>) -> Result<Vec<Link>, DnsSdError>
+{
+    let mut links: Vec<Link> = Vec::new();
+    for (kind, socket) in sockets
+    {
+        match Link::open(interface, kind, socket, handler.clone(), dispatcher.clone())
+        {
+            Ok(link) => links.push(link),
+            Err(err) =>
+            {
+                close_links(links);
+                return Err(err);
+            }
+        }
+    }
+
+    Ok(links)
+}
+
+fn close_links(links: Vec<Link>)
+{
+    for mut link in links
+    {
+        if let Err(err) = link.close()
+        {
+            debug!("Error while closing link on interface {}: {}", link.interface, err);
+        }
+    }
+}
+
+fn listen(socket: UdpSocket, interface: u32, handler: Arc<Mutex<DiscoveryHandler>>, dispatcher: Arc<Mutex<EventDispatcher>>, stopped: Arc<AtomicBool>) -> Result<(), DnsSdError>
+{
+    let mut buffer: [u8; 4096] = [0u8; 4096];
+
+    loop
+    {
+        let received = socket.recv_from(&mut buffer);
+        if stopped.load(Ordering::SeqCst)
+        {
+            debug!("Listen thread stopped");
+            return Ok(());
+        }
+
+        let (count, addr) = match received
+        {
+            Ok((count, addr)) => (count, addr),
+            Err(err) if matches!(err.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) =>
+            {
+                // Nothing received, still look for instances that expired.
+                let events =
+                {
+                    let mut handler = handler.lock().unwrap();
+                    handler.remove_expired();
+                    handler.collect_events()
+                };
+                dispatcher.lock().unwrap().dispatch(events);
+                continue;
+            },
+            Err(err) =>
+            {
+                debug!("Failed to receive data: {}", err);
+                continue;
+            }
+        };
+
+        debug!("Received {} bytes from {}", count, addr);
 
-                // Wait for 1 second.
-                thread::sleep(std::time::Duration::from_secs(1));
+        // Only parse buffer if we are looking for services or caching everything.
+        if !handler.lock().unwrap().wants_responses()
+        {
+            continue;
+        }
+
+        let responses = match DnsSdResponse::from(&buffer[..count], count)
+        {
+            Ok(responses) => responses,
+            Err(err) =>
+            {
+                debug!("Failed to parse response: {}", err);
+                continue;
             }
+        };
+
+        debug!("Parsed response:\n{:?}", responses);
+
+        let events =
+        {
+            let mut handler = handler.lock().unwrap();
+            handler.add_responses(responses, interface);
+            handler.collect_events()
+        };
+        dispatcher.lock().unwrap().dispatch(events);
+    }
+}
+
+// Send the packets of a query on every query link, built per link as the known answers depend on
+// the interface. It is enough that one link works, so a host without an IPv6 route or with an
+// unplugged interface still finds services on the others. Without any query link, in passive mode
+// or while the only interface is down, there is nothing to send.
+fn send_query<F>(links: &[Link], queries: F) -> Result<(), DnsSdError>
+    where F: Fn(u32) -> Result<Vec<Vec<u8>>, DnsSdError>
+{
+    let query_links: Vec<&Link> = links.iter()
+        .filter(|link| link.kind == LinkKind::Query)
+        .collect();
+    if query_links.is_empty()
+    {
+        return Ok(());
+    }
+
+    let mut result = Err(DnsSdError::UdpSocketError);
+    for link in query_links
+    {
+        let sent = queries(link.interface).and_then(|queries|
+        {
+            queries.iter().try_for_each(|query| link.socket.send_to(query, link.multicast_addr).map(|_| ()))?;
+            Ok(())
         });
+        match sent
+        {
+            Ok(_) => result = Ok(()),
+            Err(err) =>
+            {
+                debug!("Failed to send query to {}: {}", link.multicast_addr, err);
+                if result.is_err()
+                {
+                    result = Err(err);
+                }
+            }
+        }
+    }
 
-        Ok(Sender
+    result
+}
+
+fn wake_listener(socket: &UdpSocket)
+{
+    // A socket bound to the unspecified address is reached through loopback. If this fails the
+    // listen thread still stops at the next read timeout. That is also the case for sockets bound
+    // to the multicast group, a wakeup sent there would go out on the network.
+    let result = socket.local_addr().and_then(|local_addr|
+    {
+        if local_addr.ip().is_multicast()
         {
-            _send_thread: Some(send_thread),
-            _listen_thread: Some(listen_thread)
-        })
+            return Ok(0);
+        }
+
+        let target = match local_addr
+        {
+            SocketAddr::V4(addr) if addr.ip().is_unspecified() => SocketAddr::new(Ipv4Addr::LOCALHOST.into(), addr.port()),
+            SocketAddr::V6(addr) if addr.ip().is_unspecified() => SocketAddr::new(Ipv6Addr::LOCALHOST.into(), addr.port()),
+            addr => addr
+        };
+
+        socket.send_to(&[], target)
+    });
+
+    if let Err(err) = result
+    {
+        debug!("Failed to wake up listen thread: {}", err);
+    }
+}
+
+fn join(thread: Option<thread::JoinHandle<Result<(), DnsSdError>>>) -> Result<(), DnsSdError>
+{
+    match thread
+    {
+        Some(thread) => thread.join().unwrap_or(Err(DnsSdError::ThreadPanicked)),
+        None => Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::time::Instant;
+    use crate::interface::default_interface;
+    use crate::socket::MULTICAST_PORT;
+    use crate::dns::PtrAnswer;
+
+    fn sender(handler: &Arc<Mutex<DiscoveryHandler>>, ip_type: &IpType, filter: InterfaceFilter, interfaces: Vec<NetworkInterface>) -> Sender
+    {
+        let dispatcher = Arc::new(Mutex::new(EventDispatcher::new()));
+        Sender::new(handler.clone(), dispatcher, ip_type, filter, interfaces).unwrap()
+    }
+
+    fn link_kinds(sender: &Sender) -> Vec<LinkKind>
+    {
+        sender.network.lock().unwrap().links.iter().map(|link| link.kind).collect()
+    }
+
+    #[test]
+    fn test_shutdown()
+    {
+        for ip_type in [IpType::V4, IpType::DualStack]
+        {
+            let handler = Arc::new(Mutex::new(DiscoveryHandler::new(ip_type.clone())));
+            let mut sender = sender(&handler, &ip_type, InterfaceFilter::Default, vec![default_interface().unwrap()]);
+
+            let start = Instant::now();
+            let _ = sender.shutdown();
+
+            // Woken up instead of waiting for the read timeout.
+            assert!(start.elapsed() < LISTEN_TIMEOUT);
+            assert!(sender.send_thread.is_none());
+            assert!(sender.monitor_thread.is_none());
+            assert!(link_kinds(&sender).is_empty());
+            assert_eq!(sender.shutdown(), Ok(()));
+
+            // The threads dropped their handles, only the sender itself holds one.
+            assert_eq!(Arc::strong_count(&handler), 2);
+        }
+    }
+
+    #[test]
+    fn test_set_mode()
+    {
+        let handler = Arc::new(Mutex::new(DiscoveryHandler::new(IpType::V4)));
+        let mut sender = sender(&handler, &IpType::V4, InterfaceFilter::Default, vec![default_interface().unwrap()]);
+        assert_eq!(link_kinds(&sender), vec![LinkKind::Query]);
+
+        sender.set_mode(DiscoveryMode::Passive).unwrap();
+        assert_eq!(link_kinds(&sender), vec![LinkKind::Multicast]);
+
+        sender.set_mode(DiscoveryMode::ActiveAndPassive).unwrap();
+        assert_eq!(link_kinds(&sender), vec![LinkKind::Query, LinkKind::Multicast]);
+
+        sender.set_mode(DiscoveryMode::Active).unwrap();
+        assert_eq!(link_kinds(&sender), vec![LinkKind::Query]);
+
+        assert_eq!(sender.shutdown(), Ok(()));
+    }
+
+    #[test]
+    fn test_passive_mode_dual_stack()
+    {
+        let interface = default_interface().unwrap();
+        let handler = Arc::new(Mutex::new(DiscoveryHandler::new(IpType::DualStack)));
+        let mut sender = sender(&handler, &IpType::DualStack, InterfaceFilter::Default, vec![interface.clone()]);
+
+        // One multicast link per IP version the interface has an address of.
+        sender.set_mode(DiscoveryMode::Passive).unwrap();
+        let expected = [!interface.ipv4.is_empty(), !interface.ipv6.is_empty()].iter().filter(|has| **has).count();
+        assert_eq!(link_kinds(&sender), vec![LinkKind::Multicast; expected]);
+
+        let network = sender.network.lock().unwrap();
+        for link in &network.links
+        {
+            assert_eq!(link.socket.local_addr().unwrap().port(), MULTICAST_PORT);
+        }
+        drop(network);
+
+        assert_eq!(sender.shutdown(), Ok(()));
+    }
+
+    #[test]
+    fn test_send_thread_survives_failed_queries()
+    {
+        let handler = Arc::new(Mutex::new(DiscoveryHandler::new(IpType::V4)));
+        let mut sender = sender(&handler, &IpType::V4, InterfaceFilter::Default, vec![default_interface().unwrap()]);
+        sender.set_query_schedule(QuerySchedule { initial_interval: Duration::from_millis(100), max_interval: Duration::from_millis(100), ..QuerySchedule::default() });
+
+        // Sending from an IPv4 socket to an IPv6 address fails on every link.
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver.set_read_timeout(Some(Duration::from_secs(3))).unwrap();
+        for link in sender.network.lock().unwrap().links.iter_mut()
+        {
+            link.multicast_addr = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), MULTICAST_PORT);
+        }
+
+        handler.lock().unwrap().add_service(String::from("_libdnssd-send._udp.local"));
+        sender.schedule_queries();
+        thread::sleep(Duration::from_millis(500));
+
+        // Working again, querying goes on.
+        for link in sender.network.lock().unwrap().links.iter_mut()
+        {
+            link.multicast_addr = receiver.local_addr().unwrap();
+        }
+
+        let mut buffer = [0u8; 1500];
+        let (count, _) = receiver.recv_from(&mut buffer).unwrap();
+        assert!(count > 12);
+        assert!(!sender.send_thread.as_ref().unwrap().is_finished());
+
+        assert_eq!(sender.shutdown(), Ok(()));
+    }
+
+    #[test]
+    fn test_monitor_reopens_links()
+    {
+        let interface = default_interface().unwrap();
+        let handler = Arc::new(Mutex::new(DiscoveryHandler::new(IpType::V4)));
+        let mut sender = sender(&handler, &IpType::V4, InterfaceFilter::Default, vec![interface]);
+
+        // The filter matches nothing, as if the interface went down.
+        let (send_commands, send_requested) = mpsc::channel();
+        let mut monitor = Monitor
+        {
+            ip_type: IpType::V4,
+            filter: InterfaceFilter::Selected(Vec::new()),
+            network: sender.network.clone(),
+            handler: handler.clone(),
+            dispatcher: Arc::new(Mutex::new(EventDispatcher::new())),
+            send_commands
+        };
+
+        monitor.check();
+        assert!(link_kinds(&sender).is_empty());
+        assert!(sender.network.lock().unwrap().interfaces.is_empty());
+        assert!(matches!(send_requested.try_recv(), Ok(SendCommand::Restart)));
+
+        // Back up again.
+        monitor.filter = InterfaceFilter::Default;
+        monitor.check();
+        assert_eq!(link_kinds(&sender), vec![LinkKind::Query]);
+        assert_eq!(sender.network.lock().unwrap().interfaces.len(), 1);
+        assert!(matches!(send_requested.try_recv(), Ok(SendCommand::Restart)));
+
+        drop(monitor);
+        assert_eq!(sender.shutdown(), Ok(()));
+    }
+
+    #[test]
+    fn test_monitor_keeps_records_of_readdressed_interface()
+    {
+        let interface = default_interface().unwrap();
+        let handler = Arc::new(Mutex::new(DiscoveryHandler::new(IpType::V4)));
+        let mut sender = sender(&handler, &IpType::V4, InterfaceFilter::Default, vec![interface.clone()]);
+
+        let service = String::from("_libdnssd-readdress._udp.local");
+        let instance = format!("printer.{}", service);
+        {
+            let mut handler = handler.lock().unwrap();
+            handler.add_service(service.clone());
+            handler.add_responses(vec![DnsSdResponse::PtrAnswer(PtrAnswer { label: service.clone(), service: instance.clone(), class: 1, ttl: 120, cache_flush: false })], interface.index);
+        }
+
+        // The interface had another address before.
+        sender.network.lock().unwrap().interfaces[0].ipv4 = vec![Ipv4Addr::new(10, 0, 0, 7)];
+
+        let (send_commands, send_requested) = mpsc::channel();
+        let mut monitor = Monitor
+        {
+            ip_type: IpType::V4,
+            filter: InterfaceFilter::Default,
+            network: sender.network.clone(),
+            handler: handler.clone(),
+            dispatcher: Arc::new(Mutex::new(EventDispatcher::new())),
+            send_commands
+        };
+
+        monitor.check();
+        assert_eq!(link_kinds(&sender), vec![LinkKind::Query]);
+        assert_eq!(sender.network.lock().unwrap().interfaces, vec![interface]);
+        assert_eq!(handler.lock().unwrap().get_instances(&service), vec![instance]);
+        assert!(matches!(send_requested.try_recv(), Ok(SendCommand::Restart)));
+
+        drop(monitor);
+        assert_eq!(sender.shutdown(), Ok(()));
     }
 }
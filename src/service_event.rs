@@ -0,0 +1,94 @@
+use std::sync::mpsc;
+use log::debug;
+
+use crate::service_instance::ServiceInstance;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ServiceEvent
+{
+    // A new instance was announced through a PTR record, it may not be resolved yet.
+    Found { service: String, instance: String },
+    // The instance has an SRV record and at least one address.
+    Resolved(ServiceInstance),
+    // Host, port, TXT data or addresses of a resolved instance changed.
+    Updated(ServiceInstance),
+    // The instance said goodbye or its PTR record expired.
+    Removed { service: String, instance: String }
+}
+
+type EventCallback = Box<dyn FnMut(&ServiceEvent) + Send>;
+
+// Hands events from the listen thread to subscribed channels and callbacks.
+pub struct EventDispatcher
+{
+    senders: Vec<mpsc::Sender<ServiceEvent>>,
+    callbacks: Vec<EventCallback>
+}
+
+impl EventDispatcher
+{
+    pub fn new() -> EventDispatcher
+    {
+        EventDispatcher
+        {
+            senders: Vec::new(),
+            callbacks: Vec::new()
+        }
+    }
+
+    pub fn subscribe(&mut self) -> mpsc::Receiver<ServiceEvent>
+    {
+        let (sender, receiver) = mpsc::channel();
+        self.senders.push(sender);
+
+        receiver
+    }
+
+    pub fn add_callback(&mut self, callback: EventCallback)
+    {
+        self.callbacks.push(callback);
+    }
+
+    pub fn dispatch(&mut self, events: Vec<ServiceEvent>)
+    {
+        for event in events
+        {
+            debug!("Service event: {:?}", event);
+
+            // Receivers that were dropped are removed.
+            self.senders.retain(|sender| sender.send(event.clone()).is_ok());
+
+            for callback in self.callbacks.iter_mut()
+            {
+                callback(&event);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::sync::{ Arc, Mutex };
+
+    #[test]
+    fn test_dispatch()
+    {
+        let mut dispatcher = EventDispatcher::new();
+        let receiver = dispatcher.subscribe();
+        let dropped = dispatcher.subscribe();
+        drop(dropped);
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let callback_seen = seen.clone();
+        dispatcher.add_callback(Box::new(move |event| callback_seen.lock().unwrap().push(event.clone())));
+
+        let event = ServiceEvent::Found { service: String::from("_hap._tcp.local"), instance: String::from("DIRIGERA._hap._tcp.local") };
+        dispatcher.dispatch(vec![event.clone()]);
+
+        assert_eq!(receiver.try_recv().unwrap(), event);
+        assert_eq!(*seen.lock().unwrap(), vec![event]);
+        assert_eq!(dispatcher.senders.len(), 1);
+    }
+}
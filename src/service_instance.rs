@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use crate::dns::SrvAnswer;
+
+// A resolved service instance, built from one consistent snapshot of the cache.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServiceInstance
+{
+    // Instance label, e.g. "DIRIGERA".
+    pub name: String,
+    // Service type without the domain, e.g. "_hap._tcp".
+    pub service_type: String,
+    pub domain: String,
+    // Host the SRV record points to, e.g. "gw2-8ff6ed210a48.local".
+    pub host: String,
+    pub port: u16,
+    pub priority: u16,
+    pub weight: u16,
+    pub addresses: Vec<IpAddr>,
+    // Raw TXT strings as received.
+    pub txt_records: Vec<String>,
+    // TXT key/value pairs with lowercased keys. Keys without '=' have no value.
+    pub txt: HashMap<String, Option<String>>,
+    // Indexes of the interfaces the instance was seen on, sorted.
+    pub interfaces: Vec<u32>
+}
+
+impl ServiceInstance
+{
+    pub(crate) fn new(service: &str, instance: &str, srv_answer: &SrvAnswer, txt_records: Vec<String>, addresses: Vec<IpAddr>, interfaces: Vec<u32>) -> ServiceInstance
+    {
+        let name = match instance.len().checked_sub(service.len() + 1)
+        {
+            Some(end) if instance.is_char_boundary(end) => &instance[..end],
+            _ => instance
+        };
+
+        let (service_type, domain) = split_service(service);
+
+        ServiceInstance
+        {
+            name: String::from(name),
+            service_type,
+            domain,
+            host: srv_answer.service.clone(),
+            port: srv_answer.port,
+            priority: srv_answer.priority,
+            weight: srv_answer.weight,
+            addresses,
+            txt: parse_txt(&txt_records),
+            txt_records,
+            interfaces
+        }
+    }
+
+    // Full instance name, e.g. "DIRIGERA._hap._tcp.local".
+    pub fn full_name(&self) -> String
+    {
+        format!("{}.{}", self.name, self.service())
+    }
+
+    // Service the instance belongs to, e.g. "_hap._tcp.local".
+    pub fn service(&self) -> String
+    {
+        if self.domain.is_empty()
+        {
+            return self.service_type.clone();
+        }
+
+        format!("{}.{}", self.service_type, self.domain)
+    }
+}
+
+// Split "_hap._tcp.local" into ("_hap._tcp", "local"). The service type ends with the protocol
+// label, which is "_tcp" or "_udp" (RFC 6763 section 7).
+fn split_service(service: &str) -> (String, String)
+{
+    let labels: Vec<&str> = service.split('.').collect();
+    match labels.iter().position(|label| label.eq_ignore_ascii_case("_tcp") || label.eq_ignore_ascii_case("_udp"))
+    {
+        Some(position) => (labels[..=position].join("."), labels[position + 1..].join(".")),
+        None => (String::from(service), String::new())
+    }
+}
+
+// Keys are case-insensitive and only the first occurrence of a key counts, RFC 6763 section 6.4.
+fn parse_txt(txt_records: &[String]) -> HashMap<String, Option<String>>
+{
+    let mut txt = HashMap::new();
+    for record in txt_records
+    {
+        let (key, value) = match record.split_once('=')
+        {
+            Some((key, value)) => (key, Some(String::from(value))),
+            None => (record.as_str(), None)
+        };
+
+        if key.is_empty()
+        {
+            continue;
+        }
+
+        txt.entry(key.to_ascii_lowercase()).or_insert(value);
+    }
+
+    txt
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_new_service_instance()
+    {
+        let srv_answer = SrvAnswer
+        {
+            label: String::from("My.Printer._ipp._tcp.local"),
+            service: String::from("printer.local"),
+            priority: 1,
+            weight: 2,
+            port: 631,
+            class: 1,
+            ttl: 120,
+            cache_flush: true
+        };
+        let txt_records = vec![String::from("rp=ipp/print"), String::from("Color=T"), String::from("color=F"), String::from("duplex"), String::from("=x")];
+        let addresses = vec![IpAddr::V4(Ipv4Addr::new(192, 168, 1, 9))];
+
+        let instance = ServiceInstance::new("_ipp._tcp.local", "My.Printer._ipp._tcp.local", &srv_answer, txt_records, addresses.clone(), vec![2]);
+
+        assert_eq!(instance.name, "My.Printer");
+        assert_eq!(instance.service_type, "_ipp._tcp");
+        assert_eq!(instance.domain, "local");
+        assert_eq!(instance.full_name(), "My.Printer._ipp._tcp.local");
+        assert_eq!(instance.service(), "_ipp._tcp.local");
+        assert_eq!(instance.host, "printer.local");
+        assert_eq!(instance.port, 631);
+        assert_eq!(instance.priority, 1);
+        assert_eq!(instance.weight, 2);
+        assert_eq!(instance.addresses, addresses);
+        assert_eq!(instance.txt_records.len(), 5);
+        assert_eq!(instance.txt.len(), 3);
+        assert_eq!(instance.txt["rp"], Some(String::from("ipp/print")));
+        assert_eq!(instance.txt["color"], Some(String::from("T")));
+        assert_eq!(instance.txt["duplex"], None);
+        assert_eq!(instance.interfaces, vec![2]);
+    }
+
+    #[test]
+    fn test_split_service()
+    {
+        assert_eq!(split_service("_hap._tcp.local"), (String::from("_hap._tcp"), String::from("local")));
+        assert_eq!(split_service("_printer._sub._http._tcp.example.com"), (String::from("_printer._sub._http._tcp"), String::from("example.com")));
+        assert_eq!(split_service("_matterc._udp.local"), (String::from("_matterc._udp"), String::from("local")));
+        assert_eq!(split_service("local"), (String::from("local"), String::new()));
+    }
+}
@@ -0,0 +1,238 @@
+use std::net::IpAddr;
+
+use crate::dns::{ AAnswer, AaaaAnswer, DnsSdResponse, PtrAnswer, SrvAnswer, TxtAnswer };
+
+// TTLs recommended by RFC 6762 section 10: records with a host name in them expire sooner so a
+// changed address is noticed.
+const HOST_RECORD_TTL: u32 = 120;
+const OTHER_RECORD_TTL: u32 = 4500;
+
+const CLASS_IN: u16 = 1;
+
+// Reported to subscribers of a ServiceResponder.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RegistrationEvent
+{
+    // A conflict showed up after the instance was registered, and it now goes by another name or
+    // host. The previous full name is given with the registration as it is now.
+    Renamed { previous: String, registration: ServiceRegistration }
+}
+
+// A service instance to advertise on the network.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServiceRegistration
+{
+    // Instance label, e.g. "Living Room".
+    pub name: String,
+    // Service type without the domain, e.g. "_http._tcp".
+    pub service_type: String,
+    pub domain: String,
+    // Host the SRV record points to, e.g. "firmware.local".
+    pub host: String,
+    pub port: u16,
+    pub priority: u16,
+    pub weight: u16,
+    // TXT strings, e.g. "path=/".
+    pub txt_records: Vec<String>,
+    // Addresses of the host. When empty, the addresses of the interface a query arrived on are used.
+    pub addresses: Vec<IpAddr>
+}
+
+impl ServiceRegistration
+{
+    // Instance in the "local" domain without TXT strings or fixed addresses.
+    pub fn new(name: &str, service_type: &str, host: &str, port: u16) -> ServiceRegistration
+    {
+        ServiceRegistration
+        {
+            name: String::from(name),
+            service_type: String::from(service_type),
+            domain: String::from("local"),
+            host: String::from(host),
+            port,
+            priority: 0,
+            weight: 0,
+            txt_records: Vec::new(),
+            addresses: Vec::new()
+        }
+    }
+
+    // Full instance name, e.g. "Living Room._http._tcp.local".
+    pub fn full_name(&self) -> String
+    {
+        format!("{}.{}", self.name, self.service())
+    }
+
+    // Service the instance belongs to, e.g. "_http._tcp.local".
+    pub fn service(&self) -> String
+    {
+        if self.domain.is_empty()
+        {
+            return self.service_type.clone();
+        }
+
+        format!("{}.{}", self.service_type, self.domain)
+    }
+
+    pub(crate) fn is_valid(&self) -> bool
+    {
+        !self.name.is_empty() && !self.service_type.is_empty() && !self.host.is_empty()
+    }
+
+    // Names only we may use, probed for before answering with them.
+    pub(crate) fn unique_names(&self) -> Vec<String>
+    {
+        vec![self.full_name(), self.host.clone()]
+    }
+
+    // "Living Room" becomes "Living Room (2)", "Living Room (2)" becomes "Living Room (3)".
+    pub(crate) fn rename_instance(&mut self)
+    {
+        let numbered = self.name.strip_suffix(')')
+            .and_then(|name| name.rsplit_once(" ("))
+            .and_then(|(base, number)| number.parse::<u32>().ok().map(|number| (base, number)));
+
+        self.name = match numbered
+        {
+            Some((base, number)) => format!("{} ({})", base, number + 1),
+            None => format!("{} (2)", self.name)
+        };
+    }
+
+    // "firmware.local" becomes "firmware-2.local", "firmware-2.local" becomes "firmware-3.local".
+    pub(crate) fn rename_host(&mut self)
+    {
+        let (label, domain) = match self.host.split_once('.')
+        {
+            Some((label, domain)) => (label, Some(domain)),
+            None => (self.host.as_str(), None)
+        };
+
+        let label = match label.rsplit_once('-').and_then(|(base, number)| number.parse::<u32>().ok().map(|number| (base, number)))
+        {
+            Some((base, number)) => format!("{}-{}", base, number + 1),
+            None => format!("{}-2", label)
+        };
+
+        self.host = match domain
+        {
+            Some(domain) => format!("{}.{}", label, domain),
+            None => label
+        };
+    }
+
+    // Records sent in probes for the unique names.
+    pub(crate) fn probe_records(&self, interface_addresses: &[IpAddr]) -> Vec<DnsSdResponse>
+    {
+        let mut records = vec![self.srv_record(), self.txt_record()];
+        records.extend(self.address_records(interface_addresses));
+        records
+    }
+
+    // Records sent unasked once the names are taken, RFC 6762 section 8.3.
+    pub(crate) fn announce_records(&self, interface_addresses: &[IpAddr]) -> Vec<DnsSdResponse>
+    {
+        let mut records = vec![self.ptr_record(), self.service_type_record(), self.srv_record(), self.txt_record()];
+        records.extend(self.address_records(interface_addresses));
+        records
+    }
+
+    pub(crate) fn ptr_record(&self) -> DnsSdResponse
+    {
+        DnsSdResponse::PtrAnswer(PtrAnswer { label: self.service(), service: self.full_name(), class: CLASS_IN, ttl: OTHER_RECORD_TTL, cache_flush: false })
+    }
+
+    // Record pointing DNS-SD service type enumeration at the service, RFC 6763 section 9.
+    pub(crate) fn service_type_record(&self) -> DnsSdResponse
+    {
+        let label = if self.domain.is_empty()
+        {
+            String::from("_services._dns-sd._udp")
+        }
+        else
+        {
+            format!("_services._dns-sd._udp.{}", self.domain)
+        };
+
+        DnsSdResponse::PtrAnswer(PtrAnswer { label, service: self.service(), class: CLASS_IN, ttl: OTHER_RECORD_TTL, cache_flush: false })
+    }
+
+    pub(crate) fn srv_record(&self) -> DnsSdResponse
+    {
+        DnsSdResponse::SrvAnswer(SrvAnswer
+        {
+            label: self.full_name(),
+            service: self.host.clone(),
+            priority: self.priority,
+            weight: self.weight,
+            port: self.port,
+            class: CLASS_IN,
+            ttl: HOST_RECORD_TTL,
+            cache_flush: true
+        })
+    }
+
+    pub(crate) fn txt_record(&self) -> DnsSdResponse
+    {
+        // A TXT record holds at least one string, RFC 6763 section 6.1.
+        let records = if self.txt_records.is_empty()
+        {
+            vec![String::new()]
+        }
+        else
+        {
+            self.txt_records.clone()
+        };
+
+        DnsSdResponse::TxtAnswer(TxtAnswer { label: self.full_name(), records, class: CLASS_IN, ttl: OTHER_RECORD_TTL, cache_flush: true })
+    }
+
+    // A and AAAA records of the host, from the fixed addresses if there are any.
+    pub(crate) fn address_records(&self, interface_addresses: &[IpAddr]) -> Vec<DnsSdResponse>
+    {
+        let addresses = if self.addresses.is_empty()
+        {
+            interface_addresses
+        }
+        else
+        {
+            &self.addresses
+        };
+
+        addresses.iter()
+            .map(|address| match address
+            {
+                IpAddr::V4(address) => DnsSdResponse::AAnswer(AAnswer { label: self.host.clone(), address: *address, class: CLASS_IN, ttl: HOST_RECORD_TTL, cache_flush: true }),
+                IpAddr::V6(address) => DnsSdResponse::AaaaAnswer(AaaaAnswer { label: self.host.clone(), address: *address, class: CLASS_IN, ttl: HOST_RECORD_TTL, cache_flush: true })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn test_rename()
+    {
+        let mut registration = ServiceRegistration::new("Living Room", "_http._tcp", "firmware.local", 80);
+
+        registration.rename_instance();
+        assert_eq!(registration.full_name(), "Living Room (2)._http._tcp.local");
+        registration.rename_instance();
+        assert_eq!(registration.name, "Living Room (3)");
+
+        registration.rename_host();
+        assert_eq!(registration.host, "firmware-2.local");
+        registration.rename_host();
+        assert_eq!(registration.host, "firmware-3.local");
+
+        let mut registration = ServiceRegistration::new("Printer (color)", "_ipp._tcp", "my-host", 631);
+        registration.rename_instance();
+        assert_eq!(registration.name, "Printer (color) (2)");
+        registration.rename_host();
+        assert_eq!(registration.host, "my-host-2");
+    }
+}
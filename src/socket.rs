@@ -1,11 +1,12 @@
 extern crate socket2;
 
-use std::{ net::{ IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket }, io };
+use std::{ net::{ IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, UdpSocket }, io };
 use socket2::{ Socket, Domain, Type, SockAddr, Protocol };
-use default_net;
 use lazy_static::lazy_static;
+use log::debug;
 
 use crate::dnssd_error::DnsSdError;
+use crate::interface::NetworkInterface;
 use crate::IpType;
 
 pub const MULTICAST_PORT: u16 = 5353;
@@ -38,40 +39,46 @@ fn create_socket(addr: &SocketAddr) -> io::Result<Socket>
     Ok(socket)
 }
 
-fn get_default_interface() -> u32
+// Join the multicast group on MULTICAST_IPV4_SOCKET or MULTICAST_IPV6_SOCKET. Responses sent from
+// the socket go out on the same interface with an IP TTL of 255, RFC 6762 section 11.
+pub fn join_multicast(addr: SocketAddr, interface: &NetworkInterface) -> Result<UdpSocket, DnsSdError>
 {
-    let default_interface = match default_net::get_default_interface()
-    {
-        Ok(interface) => interface.index,
-        Err(_) => 0 // TODO: Improve error handling.
-    };
-
-    default_interface
-}
+    let ip_addr = addr.ip();
+    let socket = create_socket(&addr)?;
 
-pub fn join_multicast(ip_type: &IpType) -> Result<UdpSocket, DnsSdError>
-{
-    let addr: SocketAddr = match ip_type
+    // Only deliver traffic from the interface joined on this socket, not from groups joined by
+    // other sockets, so records are tagged with the right interface.
+    #[cfg(target_os = "linux")]
+    match ip_addr
     {
-        IpType::V4 => *MULTICAST_IPV4_SOCKET,
-        IpType::V6 => *MULTICAST_IPV6_SOCKET
+        IpAddr::V4(_) => socket.set_multicast_all_v4(false)?,
+        IpAddr::V6(_) => socket.set_multicast_all_v6(false)?
     };
-    let ip_addr = addr.ip();
-    let socket = create_socket(&addr)?;
 
     match ip_addr
     {
         IpAddr::V4(ref mdns_v4) =>
         {
-            socket.join_multicast_v4(mdns_v4, &Ipv4Addr::UNSPECIFIED)?;
+            let address = interface.ipv4.first().unwrap_or(&Ipv4Addr::UNSPECIFIED);
+            socket.join_multicast_v4(mdns_v4, address)?;
+            socket.set_multicast_if_v4(address)?;
+            socket.set_multicast_ttl_v4(255)?;
         },
         IpAddr::V6(ref mdns_v6) =>
         {
-            socket.join_multicast_v6(mdns_v6, get_default_interface())?;
+            socket.join_multicast_v6(mdns_v6, interface.index)?;
+            socket.set_multicast_if_v6(interface.index)?;
+            socket.set_multicast_hops_v6(255)?;
             socket.set_only_v6(true)?;
         }
     };
 
+    // The IPv6 group is link-local, it can only be bound with the interface as scope.
+    let addr = match addr
+    {
+        SocketAddr::V6(addr) => SocketAddr::V6(SocketAddrV6::new(*addr.ip(), addr.port(), 0, interface.index)),
+        addr => addr
+    };
     let socket = bind_multicast(socket, &addr)?;
 
     Ok(socket.into())
@@ -104,23 +111,77 @@ fn bind_multicast(socket: Socket, addr: &SocketAddr) -> io::Result<Socket>
     Ok(socket)
 }
 
-pub fn create_sender_socket(ip_type: &IpType) -> Result<UdpSocket, DnsSdError>
+// One socket per interface and IP version used, tagged with the interface index. Interfaces
+// without an address of an IP version get no socket for it.
+pub fn create_sender_sockets(ip_type: &IpType, interfaces: &[NetworkInterface]) -> Result<Vec<(u32, UdpSocket)>, DnsSdError>
+{
+    let mut sockets = Vec::new();
+    for interface in interfaces
+    {
+        if matches!(ip_type, IpType::V4 | IpType::DualStack)
+        {
+            match interface.ipv4.first()
+            {
+                Some(address) => sockets.push((interface.index, create_ipv4_sender_socket(address)?)),
+                None => debug!("No IPv4 address on interface {}", interface.name)
+            }
+        }
+
+        if matches!(ip_type, IpType::V6 | IpType::DualStack)
+        {
+            if interface.ipv6.is_empty()
+            {
+                debug!("No IPv6 address on interface {}", interface.name);
+            }
+            else
+            {
+                sockets.push((interface.index, create_ipv6_sender_socket(interface.index)?));
+            }
+        }
+    }
+
+    if sockets.is_empty()
+    {
+        return Err(DnsSdError::InterfaceNotFound);
+    }
+
+    Ok(sockets)
+}
+
+// Sockets joined to the mDNS multicast group on an interface, one per IP version used.
+pub fn create_multicast_sockets(ip_type: &IpType, interface: &NetworkInterface) -> Result<Vec<UdpSocket>, DnsSdError>
+{
+    let mut sockets = Vec::new();
+    if matches!(ip_type, IpType::V4 | IpType::DualStack) && !interface.ipv4.is_empty()
+    {
+        sockets.push(join_multicast(*MULTICAST_IPV4_SOCKET, interface)?);
+    }
+
+    if matches!(ip_type, IpType::V6 | IpType::DualStack) && !interface.ipv6.is_empty()
+    {
+        sockets.push(join_multicast(*MULTICAST_IPV6_SOCKET, interface)?);
+    }
+
+    Ok(sockets)
+}
+
+// Multicast address to send queries to from a socket.
+pub fn multicast_addr_for(socket: &UdpSocket) -> Result<SocketAddr, DnsSdError>
 {
-    match ip_type
+    match socket.local_addr()?
     {
-        IpType::V4 => create_ipv4_sender_socker(),
-        IpType::V6 => create_ipv6_sender_socket()
+        SocketAddr::V4(_) => Ok(*MULTICAST_IPV4_SOCKET),
+        SocketAddr::V6(_) => Ok(*MULTICAST_IPV6_SOCKET)
     }
 }
 
-fn create_ipv6_sender_socket() -> Result<UdpSocket, DnsSdError>
+fn create_ipv6_sender_socket(interface: u32) -> Result<UdpSocket, DnsSdError>
 {
     let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
 
-    // Sometimes MacOS will send a query on the wrong interface, causing no route to host error.
-    // Force default interface. Should not make a difference on other platforms.
-    // TODO: Find out why this happens.
-    socket.set_multicast_if_v6(get_default_interface())?;
+    // Without an interface MacOS sometimes sends the query on the wrong one, causing a no route to
+    // host error.
+    socket.set_multicast_if_v6(interface)?;
 
     socket.bind(&SockAddr::from(SocketAddr::new(
         Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0).into(),
@@ -130,12 +191,14 @@ fn create_ipv6_sender_socket() -> Result<UdpSocket, DnsSdError>
     Ok(socket.into())
 }
 
-fn create_ipv4_sender_socker() -> Result<UdpSocket, DnsSdError>
+fn create_ipv4_sender_socket(address: &Ipv4Addr) -> Result<UdpSocket, DnsSdError>
 {
     let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
 
+    // Bound to the interface address so unicast responses come back on the same interface.
+    socket.set_multicast_if_v4(address)?;
     socket.bind(&SockAddr::from(SocketAddr::new(
-        Ipv4Addr::new(0, 0, 0, 0).into(),
+        (*address).into(),
         0,
     )))?;
 